@@ -3,7 +3,9 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the LM3S6965, one of the few devices QEMU can emulate */
-  FLASH : ORIGIN = 0x00000000, LENGTH = 192K
+  /* the pages 0x27000 to 0x2EFFF hold the measurement log, the last page (0x2F000) the network
+     configuration */
+  FLASH : ORIGIN = 0x00000000, LENGTH = 156K
   RAM : ORIGIN = 0x20000000, LENGTH = 24K
   CONF : ORIGIN = 0x10001080, LENGTH = 128
 }
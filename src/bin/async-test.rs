@@ -1,48 +1,55 @@
 #![no_std]
 #![no_main]
 
+use common::executor;
+use common::p0;
 use common::rng2;
-use core::{cell::Cell, future, task::Waker};
-use core::{
-    future::Future,
-    pin::Pin,
-    ptr::null,
-    task::{Context, RawWaker, RawWakerVTable},
-};
-use cortex_m::interrupt::Mutex;
+use common::timer;
+use common::twim;
 use nrf52810_pac as pac;
-use pac::{interrupt, NVIC};
 use panic_halt as _;
 
-static THREAD_STORE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0u32));
-static RWV: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
-
 #[cortex_m_rt::entry]
 fn main() -> ! {
-    let device = pac::Peripherals::take().unwrap();
+    let mut device = pac::Peripherals::take().unwrap();
     let mut core = pac::CorePeripherals::take().unwrap();
     let mut rng = rng2::Rng2::new(device.RNG, &mut core.NVIC);
-    // let rng_future: Pin<&mut Future<Output = u8>> = Pin::new(&mut rng);
-    let mut rng_future: Pin<&mut Future<Output = u8>> = unsafe { Pin::new_unchecked(&mut rng) };
+    let mut timer = timer::Timer::new(&mut device.TIMER0, &mut core.NVIC);
+    let mut scl = p0::Pin::take(23).unwrap().into_open_drain(p0::Pull::PullUp);
+    let mut sda = p0::Pin::take(22).unwrap().into_open_drain(p0::Pull::PullUp);
+    let mut twim = twim::Twim::new(
+        &mut device.TWIM0,
+        &mut device.TIMER1,
+        &mut core.NVIC,
+        &mut scl,
+        &mut sda,
+        twim::Frequency::K400,
+    );
 
     loop {
-        // core::future::Future.poll_fn()
-        // rng_future.poll();
-        let w = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &RWV)) };
-        let mut ctx = Context::from_waker(&w);
-        rng_future.as_mut().poll(&mut ctx);
+        // the random value is generated while the sensor measures
+        let (_measurement, _value) =
+            executor::block_on(executor::join(measure(&mut twim, &mut timer), rng.value()));
+
+        executor::block_on(timer.delay_async(1_000_000));
     }
 }
 
-unsafe fn clone(_: *const ()) -> RawWaker {
-    RawWaker::new(
-        core::ptr::null(),
-        &RawWakerVTable::new(clone, wake, wake_by_ref, drop),
-    )
-}
+/// Measure temperature (°C) and humidity (%RH) with an SHT4x.
+async fn measure(
+    twim: &mut twim::Twim<'_>,
+    timer: &mut timer::Timer<'_>,
+) -> Result<(f32, f32), twim::Error> {
+    // EasyDMA cannot read the command from flash
+    let command = [0xFD];
+    let mut buffer = [0u8; 6];
 
-unsafe fn wake(_: *const ()) {}
+    twim.write_async(0x44, &command).await?;
+    timer.delay_async(10_000).await;
+    twim.read_async(0x44, &mut buffer).await?;
 
-unsafe fn wake_by_ref(_: *const ()) {}
+    let temperature = -45.0 + 175.0 * u16::from_be_bytes([buffer[0], buffer[1]]) as f32 / 65535.0;
+    let humidity = -6.0 + 125.0 * u16::from_be_bytes([buffer[3], buffer[4]]) as f32 / 65535.0;
 
-unsafe fn drop(_: *const ()) {}
+    Ok((temperature, humidity))
+}
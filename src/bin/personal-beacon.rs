@@ -114,7 +114,7 @@ const APP: () = {
         *ctx.resources.index += 1;
 
         ctx.resources.radio.init_transmission();
-        ctx.resources.radio.start_transmission(data);
+        ctx.resources.radio.start_transmission(data).unwrap();
         ctx.resources.rtc.clear_counter();
         // ctx.resources.rtc.enable_interrupt(hal::rtc::RtcInterrupt::Compare0, None);
     }
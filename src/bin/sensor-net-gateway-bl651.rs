@@ -15,19 +15,335 @@ use nrf52810_hal::prelude::OutputPin;
 // use nrf52810_hal::prelude::_embedded_hal_blocking_delay_DelayMs;
 use core::fmt::Write;
 use core::format_args;
+use core::sync::atomic::{compiler_fence, Ordering};
 use rtic::app;
 // use common::sht3;
+use common::batch;
+use common::crypto;
+use common::network;
+use common::nvmc;
+use common::packet;
+use common::ppi;
 use common::radio;
-use common::utils::{copy_into_array, get_key};
+use common::rtc;
+use common::timer;
+use common::tlv;
+use common::utils::get_pipe_key;
 // use embedded_hal::blocking::{i2c as i2c, delay as delay};
 
+/// Handling of the packets received on a logical address
+enum Decoding {
+    /// forward packets as received
+    Raw,
+    /// decrypt packets flagged as encrypted with the key of the logical address
+    Ccm,
+}
+
+struct PipePolicy {
+    prefix: u8,
+    decoding: Decoding,
+}
+
+const BASE0: u32 = 0xABCDABCD;
+const BASE1: u32 = 0xABCDABCD;
+
+/// Logical addresses to listen on (pipe 0 uses BASE0, all others BASE1)
+const PIPES: [Option<PipePolicy>; radio::LOGICAL_ADDRESS_COUNT] = [
+    Some(PipePolicy {
+        prefix: 0xDA,
+        decoding: Decoding::Ccm,
+    }),
+    Some(PipePolicy {
+        prefix: 0xEF,
+        decoding: Decoding::Ccm,
+    }),
+    None,
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+/// Reporting interval assigned to joining nodes (s)
+const NODE_INTERVAL_S: u16 = 60;
+
+/// Time between two time beacons (s)
+const BEACON_INTERVAL_S: u16 = 10;
+/// Number of time beacons between two measurements of the gateway's sensor (1 min)
+const SENSOR_BEACONS: u32 = 6;
+/// Longest command line accepted from the host
+const COMMAND_LEN: usize = 32;
+
+/// Reception of command lines from the host
+///
+/// `hal::uarte::Uarte` only receives blocking, so the reception is set up on the registers
+/// directly. Each byte is received into `buffer` and collected into a line.
+struct HostRx {
+    buffer: &'static mut [u8; 1],
+    line: [u8; COMMAND_LEN],
+    len: usize,
+}
+
+impl HostRx {
+    /// Start receiving; the UART must have been set up with `hal::uarte::Uarte` before.
+    fn start(buffer: &'static mut [u8; 1]) -> HostRx {
+        let device = unsafe { nrf52810_pac::Peripherals::steal() };
+
+        device
+            .UARTE0
+            .rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(buffer.as_ptr() as u32) });
+        device
+            .UARTE0
+            .rxd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(1) });
+        device
+            .UARTE0
+            .events_endrx
+            .write(|w| w.events_endrx().not_generated());
+        device.UARTE0.shorts.write(|w| w.endrx_startrx().enabled());
+        device.UARTE0.intenset.write(|w| w.endrx().set());
+        compiler_fence(Ordering::SeqCst);
+        device
+            .UARTE0
+            .tasks_startrx
+            .write(|w| w.tasks_startrx().trigger());
+
+        HostRx {
+            buffer,
+            line: [0; COMMAND_LEN],
+            len: 0,
+        }
+    }
+
+    /// Collect the received byte; returns the line once it is terminated.
+    fn on_interrupt(&mut self) -> Option<&[u8]> {
+        let device = unsafe { nrf52810_pac::Peripherals::steal() };
+
+        if device
+            .UARTE0
+            .events_endrx
+            .read()
+            .events_endrx()
+            .is_not_generated()
+        {
+            return None;
+        }
+
+        device
+            .UARTE0
+            .events_endrx
+            .write(|w| w.events_endrx().not_generated());
+        compiler_fence(Ordering::SeqCst);
+
+        match self.buffer[0] {
+            b'\n' | b'\r' => {
+                let len = self.len;
+
+                self.len = 0;
+
+                match len {
+                    0 => None,
+                    _ => Some(&self.line[..len]),
+                }
+            }
+            byte => {
+                // overlong lines are cut off and will not parse
+                if self.len < COMMAND_LEN {
+                    self.line[self.len] = byte;
+                    self.len += 1;
+                }
+
+                None
+            }
+        }
+    }
+}
+
+/// Parse a command setting the network time.
+///
+/// The command is `time <µs since the Unix epoch>`.
+fn parse_time_command(line: &[u8]) -> Option<u64> {
+    core::str::from_utf8(line)
+        .ok()?
+        .strip_prefix("time ")?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Start the transmission of a time beacon.
+///
+/// # Arguments
+///
+/// * `now` - monotonic time of the gateway (µs)
+/// * `time_offset` - offset of the network time set by the host to the monotonic time (µs)
+///
+fn start_beacon(radio: &mut radio::Radio, now: u64, time_offset: Option<u64>) {
+    let beacon = packet::TimeBeacon {
+        time: now.wrapping_add(time_offset.unwrap_or(0)),
+        interval: BEACON_INTERVAL_S,
+        absolute: time_offset.is_some(),
+    }
+    .to_bytes();
+    let beacon_wrapper: [&[u8]; 1] = [&beacon];
+
+    radio.init_transmission();
+    radio.start_transmission(&beacon_wrapper).unwrap();
+}
+
+/// Write the fields describing the reception of a packet.
+///
+/// # Arguments
+///
+/// * `timestamp` - time of the reception or, for samples of a batch, of the sample (µs since gateway
+///   start); left out for samples of a batch with an unknown age
+/// * `short` - node and slot timing of packets with a short address
+///
+fn write_reception(
+    uart: &mut impl Write,
+    pipe: usize,
+    rssi: u8,
+    timestamp: Option<u64>,
+    relayed: Option<&packet::Relayed>,
+    short: Option<(&network::Node, i32)>,
+) -> core::fmt::Result {
+    uart.write_fmt(format_args!(
+        "{{\
+    \"type\": \"gateway-bl651-radio\",\
+    \"pipe\": {},\
+    \"rssi\": -{},",
+        pipe, rssi
+    ))?;
+
+    if let Some(timestamp) = timestamp {
+        uart.write_fmt(format_args!("\"timestamp\": {},", timestamp))?;
+    }
+
+    if let Some(relayed) = relayed {
+        uart.write_fmt(format_args!(
+            "\"hops\": {},\"relayId\": \"{:0>16x}\",",
+            relayed.hops, relayed.relay_id
+        ))?;
+    }
+
+    if let Some((node, timing)) = short {
+        uart.write_fmt(format_args!(
+            "\"shortAddress\": {},\"deviceId\": \"{:0>16x}\",\"slotError\": {},",
+            node.short_address, node.device_id, timing
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Write the records of a payload in the self-describing format as JSON array.
+fn write_records(uart: &mut impl Write, records: tlv::Records) -> core::fmt::Result {
+    uart.write_str("\"records\": [")?;
+
+    for (cntr, record) in records.enumerate() {
+        if cntr > 0 {
+            uart.write_str(",")?;
+        }
+
+        let statistic = match record.kind & tlv::STATISTIC_MASK {
+            tlv::STATISTIC_MIN => ",\"statistic\": \"min\"",
+            tlv::STATISTIC_MAX => ",\"statistic\": \"max\"",
+            tlv::STATISTIC_MEAN => ",\"statistic\": \"mean\"",
+            _ => "",
+        };
+
+        uart.write_fmt(format_args!(
+            "{{\"kind\": {},\"unit\": {},\"value\": {}{}}}",
+            record.kind & !tlv::STATISTIC_MASK,
+            record.unit,
+            record.scaled(),
+            statistic
+        ))?;
+    }
+
+    uart.write_str("],")
+}
+
+/// Decrypt a join request with the key of the logical address it was received on.
+///
+/// Returns `None` for other packets and for join requests that cannot be authenticated.
+fn decrypt_join_request(
+    ccm: &mut hal::ccm::Ccm,
+    key: [u8; 16],
+    data: &[u8],
+) -> Option<packet::JoinRequest> {
+    if packet::packet_type(data)? != packet::TYPE_JOIN_REQUEST | packet::FLAG_ENCRYPTED
+        || data.len() < 10
+    {
+        return None;
+    }
+
+    let mut iv = [0u8; 8];
+    let mut plain = [0u8; packet::JOIN_REQUEST_LEN];
+
+    iv.copy_from_slice(&data[2..10]);
+
+    if crypto::decrypt(ccm, key, iv, &data[10..], &mut plain)? != packet::JOIN_REQUEST_LEN {
+        return None;
+    }
+
+    packet::JoinRequest::from_bytes(&plain)
+}
+
+/// Result of handling a received packet
+enum Content {
+    /// the node has been enrolled and the join accept is sent
+    Join {
+        request: packet::JoinRequest,
+        node: network::Node,
+    },
+    /// packet of an enrolled node; the decrypted payload has `len` bytes and the packet was received
+    /// `timing` µs after the start of the node's slot
+    Short {
+        node: network::Node,
+        len: usize,
+        timing: i32,
+        /// the payload is a batch of samples
+        batch: bool,
+    },
+    /// any other packet
+    Other,
+    /// packet that is not written out
+    Invalid,
+}
+
+/// Get the logical addresses to listen on as configured in `PIPES`.
+fn rx_addresses() -> radio::AddressConfig {
+    let mut addresses = radio::AddressConfig {
+        base0: BASE0,
+        base1: BASE1,
+        prefixes: [0; radio::LOGICAL_ADDRESS_COUNT],
+        enabled: 0,
+    };
+
+    for (pipe, policy) in PIPES.iter().enumerate() {
+        if let Some(policy) = policy {
+            addresses.prefixes[pipe] = policy.prefix;
+            addresses.enabled |= 1 << pipe;
+        }
+    }
+
+    addresses
+}
+
 #[app(device = nrf52810_pac, peripherals = true)]
 const APP: () = {
     struct Resources {
         uart: hal::uarte::Uarte<nrf52810_hal::pac::UARTE0>,
         radio: radio::Radio,
         delay: hal::delay::Delay,
-        rtc: hal::rtc::Rtc<nrf52810_pac::RTC0>,
+        rtc: hal::rtc::Rtc<nrf52810_pac::RTC1>,
+        monotonic: rtc::Rtc<'static>,
+        timer: timer::Timer<'static>,
         i2c: hal::twim::Twim<nrf52810_pac::TWIM0>,
         device_id: u64,
         part_id: u32,
@@ -39,13 +355,31 @@ const APP: () = {
         led_red:
             nrf52810_hal::gpio::p0::P0_23<nrf52810_hal::gpio::Output<nrf52810_hal::gpio::PushPull>>,
         ccm: hal::ccm::Ccm,
-        key: [u8; 16],
+        keys: [[u8; 16]; radio::LOGICAL_ADDRESS_COUNT],
+        rng: hal::rng::Rng,
+        nvmc: nvmc::Nvmc,
+        nodes: network::NodeTable,
+        host_rx: HostRx,
+        #[init(None)]
+        time_offset: Option<u64>,
+        #[init(0)]
+        beacon_count: u32,
+        #[init(false)]
+        beacon_pending: bool,
+        #[init(false)]
+        transmitting: bool,
     }
 
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
+        static mut RX_BUFFERS: radio::RxBuffers =
+            [[0; radio::PACKET_BUFFER_LEN]; radio::RX_BUFFER_COUNT];
+        static mut RTC0: Option<nrf52810_pac::RTC0> = None;
+        static mut TIMER0: Option<nrf52810_pac::TIMER0> = None;
+        static mut HOST_RX_BUFFER: [u8; 1] = [0; 1];
+
         let device: nrf52810_pac::Peripherals = cx.device;
-        let core = cx.core;
+        let mut core = cx.core;
         let port0 = hal::gpio::p0::Parts::new(device.P0);
         let mut led_green = port0.p0_24.into_push_pull_output(Level::Low);
         let mut led_red = port0.p0_23.into_push_pull_output(Level::Low);
@@ -56,7 +390,7 @@ const APP: () = {
             rts: None, // cts: Some(port0.p0_07.into_floating_input().degrade()),
                        // rts: Some(port0.p0_05.into_push_pull_output(Level::Low).degrade())
         };
-        let uart = hal::uarte::Uarte::new(
+        let mut uart = hal::uarte::Uarte::new(
             device.UARTE0,
             pins,
             hal::uarte::Parity::EXCLUDED,
@@ -87,17 +421,46 @@ const APP: () = {
             .enable_ext_hfosc();
 
         // set up RTC
-        let mut rtc = hal::rtc::Rtc::new(device.RTC0, 3276).unwrap(); // => 10Hz
-        rtc.set_compare(hal::rtc::RtcCompareReg::Compare0, 600)
-            .unwrap(); // => 1 min
+        // the first beacon is sent after a second; the following ones at multiples of the beacon interval
+        let mut rtc = hal::rtc::Rtc::new(device.RTC1, 0).unwrap(); // => 32768 Hz
+        rtc.set_compare(hal::rtc::RtcCompareReg::Compare0, 32768)
+            .unwrap(); // => 1 s
         rtc.enable_event(hal::rtc::RtcInterrupt::Compare0);
         rtc.enable_interrupt(hal::rtc::RtcInterrupt::Compare0, None);
         rtc.enable_counter();
 
         // set up radio
-        let radio = radio::Radio::new(device.RADIO);
+        let mut radio = radio::Radio::new(device.RADIO);
+        radio.set_rx_buffers(RX_BUFFERS).unwrap();
         radio.init_reception();
-        radio.start_reception();
+
+        radio.set_rx_addresses(&rx_addresses());
+
+        let mut keys = [[0u8; 16]; radio::LOGICAL_ADDRESS_COUNT];
+
+        for (pipe, policy) in PIPES.iter().enumerate() {
+            if policy.is_some() {
+                keys[pipe] = get_pipe_key(pipe as u8);
+            }
+        }
+
+        // set up time stamping
+        // the RTC provides the time base; the timer captures the time of the address event
+        let mut monotonic = rtc::Rtc::new(RTC0.get_or_insert(device.RTC0), &mut core.NVIC);
+        monotonic.set_prescaler(0).unwrap();
+        monotonic.start_monotonic();
+        let mut timer = timer::Timer::new(TIMER0.get_or_insert(device.TIMER0), &mut core.NVIC);
+        timer.start_free_running();
+        let mut ppi = ppi::Ppi::new(device.PPI);
+        ppi.connect(
+            0,
+            radio.event_address_endpoint(),
+            timer.task_capture_endpoint(1).unwrap(),
+        )
+        .unwrap();
+        ppi.enable(0);
+
+        radio.start_reception().unwrap();
 
         // get device id
         let device_id = ((device.FICR.deviceid[1].read().bits() as u64) << 32)
@@ -107,11 +470,25 @@ const APP: () = {
         // set up ccm
         let ccm = hal::ccm::Ccm::init(device.CCM, device.AAR, hal::ccm::DataRate::_2Mbit);
 
+        // load the enrolled nodes
+        let nvmc = nvmc::Nvmc::new(device.NVMC);
+        let nodes = network::NodeTable::load(&nvmc);
+
+        for node in nodes.iter() {
+            uart.write_fmt(format_args!(
+                "{{\"type\": \"gateway-bl651-node\",\"deviceId\": \"{:0>16x}\",\"shortAddress\": {},\"slot\": {},\"slotOffset\": {}}}\n",
+                node.device_id, node.short_address, node.slot, node.slot_offset()
+            ))
+            .unwrap();
+        }
+
         init::LateResources {
             uart: uart,
             radio: radio,
             delay: delay,
             rtc: rtc,
+            monotonic: monotonic,
+            timer: timer,
             i2c: i2c,
             device_id: device_id,
             part_id: part_id,
@@ -119,15 +496,52 @@ const APP: () = {
             led_green: led_green,
             led_red: led_red,
             ccm: ccm,
-            key: get_key(),
+            keys: keys,
+            rng: hal::rng::Rng::new(device.RNG),
+            nvmc: nvmc,
+            nodes: nodes,
+            host_rx: HostRx::start(HOST_RX_BUFFER),
         }
     }
 
-    #[task(binds = RTC0, resources = [uart, rtc, i2c, delay, device_id, part_id, sensor_id, index, led_green])]
-    fn rtc_handler(ctx: rtc_handler::Context) {
+    #[task(binds = RTC1, resources = [uart, rtc, radio, monotonic, i2c, delay, device_id, part_id, sensor_id, index, led_green, time_offset, beacon_count, beacon_pending, transmitting])]
+    fn rtc_handler(mut ctx: rtc_handler::Context) {
         ctx.resources
             .rtc
             .reset_event(hal::rtc::RtcInterrupt::Compare0);
+
+        // schedule the next beacon at the next multiple of the beacon interval in network time, so
+        // that the beacons stay clear of the nodes' slots
+        let now = ctx.resources.monotonic.lock(|monotonic| monotonic.micros());
+        let time_offset = ctx.resources.time_offset.lock(|time_offset| *time_offset);
+        let network = now.wrapping_add(time_offset.unwrap_or(0));
+        let interval = BEACON_INTERVAL_S as u64 * 1_000_000;
+        let mut until_next = interval - network % interval;
+
+        // the compare event may have come a little early
+        if until_next < interval / 2 {
+            until_next += interval;
+        }
+
+        let compare = ctx.resources.rtc.get_counter() + ((until_next << 15) / 1_000_000) as u32;
+        ctx.resources
+            .rtc
+            .set_compare(hal::rtc::RtcCompareReg::Compare0, compare & 0xFF_FFFF)
+            .unwrap();
+
+        // the beacon is sent from the radio handler once the radio has stopped
+        *ctx.resources.beacon_pending = true;
+
+        if !*ctx.resources.transmitting {
+            ctx.resources.radio.disable();
+        }
+
+        *ctx.resources.beacon_count += 1;
+
+        if *ctx.resources.beacon_count % SENSOR_BEACONS != 0 {
+            return;
+        }
+
         ctx.resources.led_green.set_high().unwrap();
         // ctx.resources.rtc.disable_interrupt(hal::rtc::RtcInterrupt::Compare0, None);
         let mut sht3 = common::sht3::SHT3::new(ctx.resources.i2c, ctx.resources.delay);
@@ -135,90 +549,463 @@ const APP: () = {
         ctx.resources.uart.write_fmt(format_args!("{{\"type\":\"gateway-bl651-sensor\",\"message\":{{\"mcuId\":\"{:0>8x}-{:0>16x}\",\"index\":{},\"sensorId\":\"{:0>4x}\",\"temperature\":{},\"humidity\":{}}}}}\n", ctx.resources.part_id, ctx.resources.device_id, ctx.resources.index, ctx.resources.sensor_id, meas.temperature, meas.humidity)).unwrap();
         *ctx.resources.index += 1;
         ctx.resources.led_green.set_low().unwrap();
-        ctx.resources.rtc.clear_counter();
         // ctx.resources.rtc.enable_interrupt(hal::rtc::RtcInterrupt::Compare0, None);
     }
 
-    #[task(binds = RADIO, resources = [uart, radio, led_red, ccm, key])]
-    fn radio_handler(ctx: radio_handler::Context) {
+    #[task(binds = UARTE0_UART0, priority = 2, resources = [host_rx, monotonic, time_offset], spawn = [report_time])]
+    fn uart_handler(ctx: uart_handler::Context) {
+        if let Some(time) = ctx
+            .resources
+            .host_rx
+            .on_interrupt()
+            .and_then(parse_time_command)
+        {
+            *ctx.resources.time_offset = Some(time.wrapping_sub(ctx.resources.monotonic.micros()));
+            // the confirmation is written at a lower priority as the UART is shared with the other tasks
+            ctx.spawn.report_time().ok();
+        }
+    }
+
+    #[task(resources = [uart, monotonic, time_offset])]
+    fn report_time(mut ctx: report_time::Context) {
+        let now = ctx.resources.monotonic.lock(|monotonic| monotonic.micros());
+        let time_offset = ctx.resources.time_offset.lock(|time_offset| *time_offset);
+
+        if let Some(time_offset) = time_offset {
+            ctx.resources
+                .uart
+                .write_fmt(format_args!(
+                    "{{\"type\": \"gateway-bl651-time\",\"time\": {}}}\n",
+                    now.wrapping_add(time_offset)
+                ))
+                .unwrap();
+        }
+    }
+
+    /// Write the nodes enrolled by the radio handler to the flash once the join accept is sent.
+    #[task(resources = [nvmc, nodes])]
+    fn store_nodes(ctx: store_nodes::Context) {
+        // nodes that could not be written are written with the next enrollment
+        ctx.resources.nodes.store(ctx.resources.nvmc).ok();
+    }
+
+    #[task(binds = RADIO, resources = [uart, radio, monotonic, timer, led_red, ccm, keys, rng, nodes, time_offset, beacon_pending, transmitting], spawn = [store_nodes])]
+    fn radio_handler(mut ctx: radio_handler::Context) {
         let radio = ctx.resources.radio;
 
         radio.clear_all();
 
-        let event_address = radio.event_address();
-        let event_payload = radio.event_payload();
-        let event_end = radio.event_end();
         let event_disabled = radio.event_disabled();
-        let event_rssiend = radio.event_rssiend();
-        let event_crcok = radio.event_crcok();
 
         radio.event_reset_all();
 
-        if event_address && event_payload && event_end && event_crcok && event_rssiend {
-            if let Some(data) = radio.payload() {
-                ctx.resources.led_red.set_high().unwrap();
-                ctx.resources
-                    .uart
-                    .write_fmt(format_args!(
-                        "{{\
-                    \"type\": \"gateway-bl651-radio\",\
-                    \"rssi\": -{},\
-                    \"data\": \"",
-                        radio.rssi()
-                    ))
-                    .unwrap();
+        if !event_disabled {
+            return;
+        }
+
+        let now = ctx.resources.monotonic.lock(|monotonic| monotonic.micros());
+        let time_offset = ctx.resources.time_offset.lock(|time_offset| *time_offset);
+
+        if *ctx.resources.transmitting {
+            // a response or a beacon has been sent; send a pending beacon or return to reception
+            if *ctx.resources.beacon_pending {
+                *ctx.resources.beacon_pending = false;
+                start_beacon(radio, now, time_offset);
+
+                return;
+            }
+
+            *ctx.resources.transmitting = false;
+            radio.init_reception();
+            radio.set_rx_addresses(&rx_addresses());
+            radio.start_reception().unwrap();
+
+            return;
+        }
+
+        // the address event of the packet has been captured in CC[1]
+        let since_address = ctx
+            .resources
+            .timer
+            .capture(2)
+            .unwrap()
+            .wrapping_sub(ctx.resources.timer.captured(1).unwrap());
+
+        radio.complete_reception(now.saturating_sub(since_address as u64));
+
+        let token = radio.take_frame();
+        let mut response = [0u8; radio::MAX_PAYLOAD_LEN];
+        let mut response_len = 0;
+        let mut plain = [0u8; crypto::MAX_PLAIN_LEN];
+        let content = match &token {
+            Some(token) => {
+                let frame = radio.frame(token);
+                let pipe = frame.address_match as usize;
+                let policy = PIPES.get(pipe).and_then(|policy| policy.as_ref());
+                let (data, is_relayed) = match packet::Relayed::from_bytes(frame.payload) {
+                    Some(relayed) => (relayed.inner, true),
+                    None => (frame.payload, false),
+                };
+
+                match (frame.crc_ok, policy) {
+                    // packets of the gateway forwarded by relays
+                    (true, Some(_)) if packet::is_downlink(data) => Content::Invalid,
+                    (true, Some(_)) => {
+                        if let Some(request) =
+                            decrypt_join_request(ctx.resources.ccm, ctx.resources.keys[pipe], data)
+                        {
+                            // enroll the node and send the parameters encrypted with the key of the logical address
+                            let mut session_key = [0u8; 16];
+                            let mut iv = [0u8; 8];
+
+                            ctx.resources.rng.random(&mut session_key);
+                            ctx.resources.rng.random(&mut iv);
+
+                            let node = ctx.resources.nodes.enroll(
+                                request.device_id,
+                                request.nonce,
+                                session_key,
+                            );
+                            let cipher_len = match node {
+                                Some(node) => {
+                                    let accept = packet::JoinAccept {
+                                        device_id: node.device_id,
+                                        nonce: request.nonce,
+                                        short_address: node.short_address,
+                                        session_key: node.session_key,
+                                        interval: NODE_INTERVAL_S,
+                                        slot_offset: node.slot_offset(),
+                                    };
+
+                                    crypto::encrypt(
+                                        ctx.resources.ccm,
+                                        ctx.resources.keys[pipe],
+                                        iv,
+                                        &accept.to_bytes(),
+                                        &mut response[10..],
+                                    )
+                                }
+                                None => None,
+                            };
+
+                            match (node, cipher_len) {
+                                (Some(node), Some(cipher_len)) => {
+                                    response[0..2].copy_from_slice(
+                                        &(packet::TYPE_JOIN_ACCEPT | packet::FLAG_ENCRYPTED)
+                                            .to_le_bytes()[..],
+                                    );
+                                    response[2..10].copy_from_slice(&iv);
+                                    response_len = 10 + cipher_len;
+
+                                    Content::Join { request, node }
+                                }
+                                _ => Content::Invalid,
+                            }
+                        } else if packet::packet_type(data)
+                            == Some(packet::TYPE_JOIN_REQUEST | packet::FLAG_ENCRYPTED)
+                        {
+                            // join requests that cannot be authenticated (e.g. of another network)
+                            Content::Invalid
+                        } else if let Some(header) = packet::ShortHeader::from_bytes(data) {
+                            let node = ctx
+                                .resources
+                                .nodes
+                                .find_by_short_address(header.short_address)
+                                .copied();
+                            let len = match node {
+                                Some(node) => crypto::decrypt(
+                                    ctx.resources.ccm,
+                                    node.session_key,
+                                    header.iv,
+                                    header.cipher,
+                                    &mut plain,
+                                ),
+                                None => None,
+                            };
+
+                            match (node, len) {
+                                (Some(node), Some(len)) if len >= 4 => {
+                                    // the timing of relayed packets includes the delays of the relays
+                                    let timing = match is_relayed {
+                                        true => 0,
+                                        false => network::slot_timing(
+                                            frame.timestamp.wrapping_add(time_offset.unwrap_or(0)),
+                                            node.slot_offset(),
+                                            NODE_INTERVAL_S,
+                                        ),
+                                    };
+
+                                    if header.package_type & packet::FLAG_ACK_REQUEST
+                                        == packet::FLAG_ACK_REQUEST
+                                    {
+                                        let ack = packet::Ack {
+                                            device_id: node.short_address as u64,
+                                            index: u32::from_le_bytes([
+                                                plain[0], plain[1], plain[2], plain[3],
+                                            ]),
+                                            rssi: frame.rssi,
+                                            timing,
+                                        };
+
+                                        response[..packet::ACK_LEN]
+                                            .copy_from_slice(&ack.to_bytes());
+                                        response_len = packet::ACK_LEN;
+                                    }
+
+                                    Content::Short {
+                                        node,
+                                        len,
+                                        timing,
+                                        batch: header.package_type & packet::TYPE_MASK
+                                            == packet::TYPE_SHORT_BATCH,
+                                    }
+                                }
+                                _ => Content::Invalid,
+                            }
+                        } else {
+                            if let Some((device_id, index)) = packet::ack_request(data) {
+                                let ack = packet::Ack {
+                                    device_id,
+                                    index,
+                                    rssi: frame.rssi,
+                                    timing: 0,
+                                };
+
+                                response[..packet::ACK_LEN].copy_from_slice(&ack.to_bytes());
+                                response_len = packet::ACK_LEN;
+                            }
 
-                let mut package_type = u16::from_le_bytes([data[0], data[1]]);
-
-                if data.len() > 16 && (package_type & 0x8000 == 0x8000) {
-                    let mut iv = [0u8; 8];
-                    copy_into_array(&data[2..10], &mut iv);
-                    let mut ccm_data = hal::ccm::CcmData::new(*ctx.resources.key, iv);
-                    let mut data_enc = [0u8; 258];
-                    data_enc[1] = (data.len() - 2 - 8) as u8;
-                    copy_into_array(&data[10..], &mut data_enc[3..]);
-                    let mut data_plain = [0u8; 254];
-                    let mut scratch = [0u8; 274];
+                            Content::Other
+                        }
+                    }
+                    _ => Content::Invalid,
+                }
+            }
+            None => Content::Invalid,
+        };
+
+        // send the response before writing out the frame to keep the node's receive window short;
+        // otherwise continue receiving into the next buffer while the frame is processed
+        if response_len > 0 {
+            let response_wrapper: [&[u8]; 1] = [&response[..response_len]];
+
+            radio.init_transmission();
+            radio.start_transmission(&response_wrapper).unwrap();
+            *ctx.resources.transmitting = true;
+        } else if *ctx.resources.beacon_pending {
+            // the reception has been stopped for the beacon
+            *ctx.resources.beacon_pending = false;
+            start_beacon(radio, now, time_offset);
+            *ctx.resources.transmitting = true;
+        } else {
+            radio.start_reception().unwrap();
+        }
+
+        if let Some(token) = token {
+            let frame = radio.frame(&token);
+            let pipe = frame.address_match as usize;
+            let policy = PIPES.get(pipe).and_then(|policy| policy.as_ref());
+
+            match (&content, policy) {
+                (Content::Join { request, node }, Some(_)) => {
+                    // the join accept is on its way; the flash is written after this handler
+                    ctx.spawn.store_nodes().ok();
+                    ctx.resources.led_red.set_high().unwrap();
                     ctx.resources
-                        .ccm
-                        .decrypt_packet(&mut ccm_data, &mut data_plain, &data_enc, &mut scratch)
+                        .uart
+                        .write_fmt(format_args!(
+                            "{{\
+                    \"type\": \"gateway-bl651-join\",\
+                    \"pipe\": {},\
+                    \"rssi\": -{},\
+                    \"mcuId\": \"{:0>8x}-{:0>16x}\",\
+                    \"shortAddress\": {},\
+                    \"slot\": {},\
+                    \"slotOffset\": {}}}\n",
+                            pipe,
+                            frame.rssi,
+                            request.part_id,
+                            node.device_id,
+                            node.short_address,
+                            node.slot,
+                            node.slot_offset()
+                        ))
                         .unwrap();
+                    ctx.resources.led_red.set_low().unwrap();
+                }
+                (
+                    Content::Short {
+                        node,
+                        len,
+                        timing,
+                        batch: true,
+                    },
+                    Some(_),
+                ) => {
+                    // write out each sample of the batch like a packet of its own
+                    let relayed = packet::Relayed::from_bytes(frame.payload);
+
+                    ctx.resources.led_red.set_high().unwrap();
 
-                    package_type = package_type & 0x7FFF;
+                    for sample in batch::Samples::from_bytes(&plain[..*len])
+                        .into_iter()
+                        .flatten()
+                    {
+                        write_reception(
+                            ctx.resources.uart,
+                            pipe,
+                            frame.rssi,
+                            sample
+                                .age
+                                .map(|age| frame.timestamp.saturating_sub(age as u64 * 1_000_000)),
+                            relayed.as_ref(),
+                            Some((node, *timing)),
+                        )
+                        .unwrap();
+
+                        if let Some(records) = tlv::Records::from_bytes(sample.payload) {
+                            write_records(ctx.resources.uart, records).unwrap();
+                        }
 
-                    for byte in package_type.to_le_bytes().iter() {
                         ctx.resources
                             .uart
-                            .write_fmt(format_args!("{:0>2x}", *byte))
+                            .write_fmt(format_args!("\"data\": \""))
                             .unwrap();
-                    }
 
-                    for cntr in 3..data_plain[1] + 3 {
+                        for byte in packet::TYPE_SHORT_DATA
+                            .to_le_bytes()
+                            .iter()
+                            .chain(sample.index.to_le_bytes().iter())
+                            .chain(sample.payload.iter())
+                        {
+                            ctx.resources
+                                .uart
+                                .write_fmt(format_args!("{:0>2x}", byte))
+                                .unwrap();
+                        }
+
                         ctx.resources
                             .uart
-                            .write_fmt(format_args!("{:0>2x}", data_plain[cntr as usize]))
+                            .write_fmt(format_args!("\"}}\n"))
                             .unwrap();
                     }
-                } else {
-                    for byte in data {
+
+                    ctx.resources.led_red.set_low().unwrap();
+                }
+                (Content::Short { .. }, Some(policy)) | (Content::Other, Some(policy)) => {
+                    let relayed = packet::Relayed::from_bytes(frame.payload);
+                    let data = match &relayed {
+                        Some(relayed) => relayed.inner,
+                        None => frame.payload,
+                    };
+
+                    let short = match &content {
+                        Content::Short { node, timing, .. } => Some((node, *timing)),
+                        _ => None,
+                    };
+
+                    ctx.resources.led_red.set_high().unwrap();
+                    write_reception(
+                        ctx.resources.uart,
+                        pipe,
+                        frame.rssi,
+                        Some(frame.timestamp),
+                        relayed.as_ref(),
+                        short,
+                    )
+                    .unwrap();
+
+                    let records = match &content {
+                        Content::Short { len, .. } => tlv::Records::from_bytes(&plain[4..*len]),
+                        _ => match packet::packet_type(data) {
+                            Some(package_type)
+                                if package_type & packet::TYPE_MASK == packet::TYPE_SENSOR_DATA
+                                    && data.len() > packet::SENSOR_DATA_HEADER_LEN =>
+                            {
+                                tlv::Records::from_bytes(&data[packet::SENSOR_DATA_HEADER_LEN..])
+                            }
+                            _ => None,
+                        },
+                    };
+
+                    if let Some(records) = records {
+                        write_records(ctx.resources.uart, records).unwrap();
+                    }
+
+                    ctx.resources
+                        .uart
+                        .write_fmt(format_args!("\"data\": \""))
+                        .unwrap();
+
+                    let package_type = packet::packet_type(data);
+                    let decrypt = match policy.decoding {
+                        Decoding::Raw => false,
+                        Decoding::Ccm => {
+                            data.len() > 16
+                                && package_type.unwrap_or(0) & packet::FLAG_ENCRYPTED
+                                    == packet::FLAG_ENCRYPTED
+                        }
+                    };
+                    // packets that cannot be authenticated with the key of the logical address are
+                    // written out as received
+                    let decrypted = match (&content, decrypt) {
+                        (Content::Other, true) => {
+                            let mut iv = [0u8; 8];
+
+                            iv.copy_from_slice(&data[2..10]);
+                            crypto::decrypt(
+                                ctx.resources.ccm,
+                                ctx.resources.keys[pipe],
+                                iv,
+                                &data[10..],
+                                &mut plain,
+                            )
+                        }
+                        _ => None,
+                    };
+
+                    let (package_type, payload) = match (&content, package_type, decrypted) {
+                        // packets with a short address are always decrypted with the session key
+                        (Content::Short { len, .. }, Some(package_type), _) => {
+                            (Some(package_type & packet::TYPE_MASK), &plain[..*len])
+                        }
+                        (_, Some(package_type), Some(len)) => {
+                            (Some(package_type & !packet::FLAG_ENCRYPTED), &plain[..len])
+                        }
+                        // the acknowledgement request is handled by the gateway
+                        (_, Some(package_type), None) => {
+                            (Some(package_type & !packet::FLAG_ACK_REQUEST), &data[2..])
+                        }
+                        // too short for a packet type
+                        (_, None, _) => (None, data),
+                    };
+
+                    for byte in package_type
+                        .map(u16::to_le_bytes)
+                        .iter()
+                        .flatten()
+                        .chain(payload.iter())
+                    {
                         ctx.resources
                             .uart
                             .write_fmt(format_args!("{:0>2x}", byte))
                             .unwrap();
                     }
-                }
 
-                ctx.resources
-                    .uart
-                    .write_fmt(format_args!("\"}}\n"))
-                    .unwrap();
-                // ctx.resources.uart.write_fmt(format_args!("payload: {:?}\n", radio.payload())).unwrap();
-                ctx.resources.led_red.set_low().unwrap();
+                    ctx.resources
+                        .uart
+                        .write_fmt(format_args!("\"}}\n"))
+                        .unwrap();
+                    ctx.resources.led_red.set_low().unwrap();
+                }
+                _ => {}
             }
-        }
 
-        if event_disabled {
-            radio.start_reception();
+            radio.release_frame(token);
         }
     }
+
+    extern "C" {
+        fn SWI0_EGU0();
+    }
 };
@@ -0,0 +1,240 @@
+#![no_std]
+#![no_main]
+
+// pick a panicking behavior
+use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch panics
+                     // use panic_abort as _; // requires nightly
+                     // use panic_itm as _; // logs messages over ITM; requires ITM support
+                     // use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
+
+use common::packet;
+use common::radio;
+use common::rtc;
+use nrf52810_hal as hal;
+use nrf52810_hal::gpio::Level;
+use nrf52810_hal::prelude::OutputPin;
+use rtic::app;
+
+/// Time a relayed packet is remembered for detecting duplicates (µs)
+const DUPLICATE_WINDOW_MUS: u64 = 10_000_000;
+/// Time until another attempt to transmit a packet while the radio is busy (µs)
+const RETRY_MUS: u64 = 1_000;
+
+/// Packet waiting for its retransmission
+pub struct Outgoing {
+    data: [u8; radio::MAX_PAYLOAD_LEN],
+    len: usize,
+    /// reception of the original packet (ticks)
+    received: u64,
+    /// length of the original packet
+    received_len: usize,
+}
+
+/// Convert ticks of RTC1 (32768 Hz) into µs.
+fn ticks_to_micros(ticks: u64) -> u64 {
+    (ticks * 1_000_000) >> 15
+}
+
+/// Advance the time of a time beacon by the time it spent in the relay (µs).
+///
+/// Other packets are left unchanged.
+fn adjust_beacon(data: &mut [u8], delay: u64) {
+    if let Some(mut beacon) = packet::TimeBeacon::from_bytes(data) {
+        beacon.time += delay;
+        data.copy_from_slice(&beacon.to_bytes());
+    }
+}
+
+/// Transmit a packet waiting for its retransmission.
+fn transmit(radio: &mut radio::Radio, outgoing: &mut Outgoing, now: u64) {
+    // a time beacon states the network time at the start of its transmission
+    let delay = ticks_to_micros(now.saturating_sub(outgoing.received))
+        + packet::beacon_air_time_mus(outgoing.received_len);
+    adjust_beacon(
+        &mut outgoing.data[packet::RELAY_HEADER_LEN..outgoing.len],
+        delay,
+    );
+
+    let outgoing_wrapper: [&[u8]; 1] = [&outgoing.data[..outgoing.len]];
+
+    radio.init_transmission();
+    radio.start_transmission(&outgoing_wrapper).unwrap();
+}
+
+// the retransmissions are scheduled with RTC1, so that the relay keeps receiving during the
+// back-off
+#[app(device = nrf52810_pac, peripherals = true, monotonic = common::rtc::MonotonicRtc1)]
+const APP: () = {
+    struct Resources {
+        radio: radio::Radio,
+        rng: hal::rng::Rng,
+        /// packets of the nodes relayed recently
+        recent: packet::RecentPackets,
+        /// packets of the gateway relayed recently
+        recent_downlink: packet::RecentPackets,
+        relay_id: u64,
+        led:
+            nrf52810_hal::gpio::p0::P0_24<nrf52810_hal::gpio::Output<nrf52810_hal::gpio::PushPull>>,
+        /// packet to be transmitted once the radio has stopped
+        #[init(None)]
+        outgoing: Option<Outgoing>,
+        #[init(false)]
+        transmitting: bool,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+        static mut RX_BUFFERS: radio::RxBuffers =
+            [[0; radio::PACKET_BUFFER_LEN]; radio::RX_BUFFER_COUNT];
+
+        let device: nrf52810_pac::Peripherals = cx.device;
+        let port0 = hal::gpio::p0::Parts::new(device.P0);
+        let led = port0.p0_24.into_push_pull_output(Level::Low);
+
+        // set up clocks
+        hal::clocks::Clocks::new(device.CLOCK)
+            .set_lfclk_src_rc()
+            .start_lfclk()
+            .enable_ext_hfosc();
+
+        // the relay identifies itself with its device id
+        let relay_id = ((device.FICR.deviceid[1].read().bits() as u64) << 32)
+            + (device.FICR.deviceid[0].read().bits() as u64);
+
+        // set up radio
+        let mut radio = radio::Radio::new(device.RADIO);
+        radio.set_rx_buffers(RX_BUFFERS).unwrap();
+        radio.init_reception();
+        radio.start_reception().unwrap();
+
+        // the time base (RTC1 without prescaler) is started by RTIC after `init`
+        let window = rtc::MonotonicRtc1::micros_to_ticks(DUPLICATE_WINDOW_MUS);
+
+        init::LateResources {
+            radio: radio,
+            rng: hal::rng::Rng::new(device.RNG),
+            recent: packet::RecentPackets::new(window),
+            recent_downlink: packet::RecentPackets::new(window),
+            relay_id: relay_id,
+            led: led,
+        }
+    }
+
+    /// Keep the CPU running, as SysTick, which starts the scheduled tasks, stops while it sleeps.
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::nop();
+        }
+    }
+
+    /// Count the overflows of the time base.
+    #[task(binds = RTC1, priority = 2)]
+    fn rtc_handler(_: rtc_handler::Context) {
+        rtc::on_interrupt::<nrf52810_pac::RTC1>();
+    }
+
+    #[task(binds = RADIO, resources = [radio, rng, recent, recent_downlink, relay_id, led, outgoing, transmitting], schedule = [forward])]
+    fn radio_handler(ctx: radio_handler::Context) {
+        let radio = ctx.resources.radio;
+        let now = ctx.start;
+
+        radio.clear_all();
+
+        let event_disabled = radio.event_disabled();
+
+        radio.event_reset_all();
+
+        if !event_disabled {
+            return;
+        }
+
+        let transmitted = *ctx.resources.transmitting;
+
+        if transmitted {
+            // the packet has been relayed
+            *ctx.resources.transmitting = false;
+            ctx.resources.led.set_low().unwrap();
+        } else {
+            radio.complete_reception(now);
+
+            if let Some(token) = radio.take_frame() {
+                let frame = radio.frame(&token);
+                let recent = match packet::is_downlink(packet::unwrap_relayed(frame.payload)) {
+                    true => ctx.resources.recent_downlink,
+                    false => ctx.resources.recent,
+                };
+
+                // packets without an identity cannot be checked for duplicates and are not relayed
+                if let (true, Some(identity)) =
+                    (frame.crc_ok, packet::packet_identity(frame.payload))
+                {
+                    let mut outgoing = Outgoing {
+                        data: [0; radio::MAX_PAYLOAD_LEN],
+                        len: 0,
+                        received: now,
+                        received_len: frame.payload.len(),
+                    };
+
+                    if let (true, Some(len)) = (
+                        recent.insert(identity, now),
+                        packet::relay(frame.payload, *ctx.resources.relay_id, &mut outgoing.data),
+                    ) {
+                        let backoff =
+                            ctx.resources.rng.random_u8() % (packet::MAX_RELAY_BACKOFF_MS + 1);
+
+                        outgoing.len = len;
+                        // the packet is dropped if too many packets are waiting
+                        ctx.schedule
+                            .forward(
+                                now + rtc::MonotonicRtc1::micros_to_ticks(backoff as u64 * 1_000),
+                                outgoing,
+                            )
+                            .ok();
+                    }
+                }
+
+                radio.release_frame(token);
+            }
+        }
+
+        // transmit a packet that is due or return to reception
+        match ctx.resources.outgoing.take() {
+            Some(mut outgoing) => {
+                ctx.resources.led.set_high().unwrap();
+                transmit(radio, &mut outgoing, now);
+                *ctx.resources.transmitting = true;
+            }
+            None => {
+                if transmitted {
+                    radio.init_reception();
+                }
+
+                radio.start_reception().unwrap();
+            }
+        }
+    }
+
+    /// Hand a packet to the radio handler after the back-off.
+    ///
+    /// The reception is stopped; the radio handler transmits the packet once the radio is
+    /// disabled.
+    #[task(capacity = 4, resources = [radio, outgoing, transmitting], schedule = [forward])]
+    fn forward(ctx: forward::Context, outgoing: Outgoing) {
+        if ctx.resources.outgoing.is_some() || *ctx.resources.transmitting {
+            // another packet is being transmitted; try again later
+            let retry = ctx.scheduled + rtc::MonotonicRtc1::micros_to_ticks(RETRY_MUS);
+
+            ctx.schedule.forward(retry, outgoing).ok();
+
+            return;
+        }
+
+        *ctx.resources.outgoing = Some(outgoing);
+        ctx.resources.radio.disable();
+    }
+
+    extern "C" {
+        fn SWI0_EGU0();
+    }
+};
@@ -11,23 +11,118 @@ use panic_halt as _; // you can put a breakpoint on `rust_begin_unwind` to catch
 
 // use cortex_m::asm;
 // use cortex_m_rt::entry;
+use common::aggregate;
+use common::batch;
 use common::clock;
+use common::crypto;
+use common::link;
+use common::log;
 use common::mmc5603nj;
+use common::network;
+use common::nvmc;
 use common::p0;
+use common::packet;
 use common::power;
 use common::radio;
+use common::report;
 use common::rng;
 use common::rtc;
 use common::saadc;
 use common::sht4x;
+use common::sync;
 use common::timer;
+use common::tlv;
 use common::twim;
-use common::utils::{copy_into_array, get_key};
+use common::utils::{copy_into_array, get_pipe_key};
+use core::sync::atomic::{AtomicU8, Ordering};
 use nrf52810_hal as hal;
 use nrf52810_pac as pac;
 
+/// Send temperature (0.01 °C), humidity (0.5 %RH) and battery voltage (mV) as integers instead of f32
+const COMPACT_ENCODING: bool = true;
+/// Longest payload of records (version, five records with four and two with one byte values)
+const RECORDS_LEN: usize = 1 + 5 * (tlv::RECORD_HEADER_LEN + 4) + 2 * (tlv::RECORD_HEADER_LEN + 1);
+/// Longest payload of records with statistics (version, nine records with four, one with two and
+/// two with one byte values)
+const AGGREGATE_RECORDS_LEN: usize = 1
+    + 9 * (tlv::RECORD_HEADER_LEN + 4)
+    + (tlv::RECORD_HEADER_LEN + 2)
+    + 2 * (tlv::RECORD_HEADER_LEN + 1);
+/// Time between two samples (s); the samples taken between two transmissions are sent as a batch
+const SAMPLE_INTERVAL_S: u64 = 10;
+/// Send minimum, maximum and mean of temperature and humidity over the samples taken between two
+/// transmissions instead of a batch
+const AGGREGATE: bool = false;
+/// Largest number of samples sent in one batch
+const BATCH_LEN: usize = 4;
+/// Length of the records reporting the state of the flash log (two with two and one with four byte
+/// values)
+const DIAGNOSTICS_LEN: usize = 2 * (tlv::RECORD_HEADER_LEN + 2) + (tlv::RECORD_HEADER_LEN + 4);
+/// Longest plain text of a packet with the short address (batch of `BATCH_LEN` samples with the
+/// diagnostics; longer than the index and records with statistics)
+const PLAIN_LEN: usize =
+    batch::HEADER_LEN + BATCH_LEN * (batch::SAMPLE_HEADER_LEN + RECORDS_LEN) + DIAGNOSTICS_LEN;
+/// Keep samples in flash until they are acknowledged and send the samples the gateway missed
+const FLASH_LOG: bool = true;
+/// Largest number of batches sent from the flash log after a transmission
+const BACKFILL_BATCHES: u32 = 8;
+/// Only transmit if a value moved more than its threshold since the last report or the heartbeat
+/// timeout expired
+const REPORT_ON_CHANGE: bool = false;
+/// Change of the temperature that is reported (°C)
+const TEMPERATURE_THRESHOLD: f32 = 0.2;
+/// Change of the humidity that is reported (%RH)
+const HUMIDITY_THRESHOLD: f32 = 1.0;
+/// Longest time between two reports (s)
+const HEARTBEAT_S: u64 = 900;
+/// Time to wait for the acknowledgement of the gateway
+const ACK_TIMEOUT_MUS: u32 = 2_000;
+/// Longest time a transmission takes (ramp-up and the longest packet at 1 Mbit/s)
+const TX_TIMEOUT_MUS: u32 = 2_500;
+/// Time to wait for the join accept of the gateway (enrolling the node takes longer than an acknowledgement)
+const JOIN_TIMEOUT_MUS: u32 = 10_000;
+/// Prescaler of the RTC (1024 Hz); alarms can be set up to 4.5 h ahead
+const RTC_PRESCALER: u16 = 31;
+/// Logical address of the gateway the node sends on (prefix 0xEF); its key is used for joining
+const GATEWAY_PIPE: u8 = 1;
+/// Reporting interval until the gateway assigns one (s)
+const DEFAULT_INTERVAL_S: u64 = 60;
+/// Number of reports between catching time beacons of the gateway
+const SYNC_CYCLES: u32 = 60;
+/// Time to listen for a time beacon if the time of the next beacon is unknown (beacon interval and margin)
+const SEARCH_TIMEOUT_MUS: u32 = 10_500_000;
+/// Time between waking up and the start of the slot (measurement and start-up of the radio)
+const SLOT_LEAD_MUS: u64 = 20_000;
+/// Largest correction of the wake-up time derived from the timing reported by the gateway
+const MAX_SLOT_CORRECTION_MUS: i64 = 50_000;
+/// RSSI at the gateway the transmit power is adjusted for (-dBm)
+const TARGET_RSSI: u8 = 75;
+/// Deviation from the target RSSI (dB) before the transmit power is adjusted
+const RSSI_HYSTERESIS: u8 = 6;
+
+/// Relays between the gateway and the node as seen in the last packet received from the gateway
+///
+/// The node listens longer for the answers of the gateway behind relays.
+static HOPS: AtomicU8 = AtomicU8::new(0);
+
+/// Sample waiting for the next transmission
+#[derive(Clone, Copy)]
+struct Sample {
+    /// local time of the measurement (µs)
+    time: u64,
+    records: [u8; AGGREGATE_RECORDS_LEN + DIAGNOSTICS_LEN],
+    len: usize,
+    /// the sample is reported (see `report::ReportFilter`)
+    reported: bool,
+    /// address of the sample in the flash log
+    log_address: Option<u32>,
+}
+
 #[cortex_m_rt::entry]
 fn main() -> ! {
+    static mut RX_BUFFERS: radio::RxBuffers =
+        [[0; radio::PACKET_BUFFER_LEN]; radio::RX_BUFFER_COUNT];
+
     let mut device = pac::Peripherals::take().unwrap();
     let mut core = pac::CorePeripherals::take().unwrap();
 
@@ -38,12 +133,18 @@ fn main() -> ! {
     let conf_version_minor = (conf0 >> 8) as u8;
     let conf_version_patch = conf0 as u8;
 
-    if conf_version_major == 1 && conf_version_minor == 1 {
-        // disable snooze mode (pull pin 19 high)
-        device.P0.pin_cnf[19].write(|w| w.dir().output());
-        device.P0.out.write(|w| w.pin19().high());
-        device.P0.outset.write(|w| w.pin19().set());
-    }
+    // boards of version 1.1 have a snooze pin; snooze mode is disabled while the node is awake
+    let mut snooze = match conf_version_major == 1 && conf_version_minor == 1 {
+        true => Some(
+            p0::Pin::take(19)
+                .unwrap()
+                .into_output(p0::Drive::S0S1, true),
+        ),
+        false => None,
+    };
+    let mut scl = p0::Pin::take(23).unwrap().into_open_drain(p0::Pull::PullUp);
+    let mut sda = p0::Pin::take(22).unwrap().into_open_drain(p0::Pull::PullUp);
+    let mut battery_pin = p0::Pin::take(3).unwrap();
 
     let clock = clock::Clock::new(device.CLOCK);
     let mut clock = clock.start_lfclk(clock::Source::Xtal, false, false);
@@ -51,7 +152,9 @@ fn main() -> ! {
 
     // set up radio
     let mut radio = radio::Radio::new(device.RADIO);
+    radio.set_rx_buffers(RX_BUFFERS).unwrap();
     radio.set_enabled(false);
+    let mut power_control = link::PowerControl::new(TARGET_RSSI, RSSI_HYSTERESIS);
 
     // get device id
     let device_id = ((device.FICR.deviceid[1].read().bits() as u64) << 32)
@@ -63,10 +166,10 @@ fn main() -> ! {
         let mut timer = timer::Timer::new(&mut device.TIMER0, &mut core.NVIC);
         let mut twim = twim::Twim::new(
             &mut device.TWIM0,
+            &mut device.TIMER1,
             &mut core.NVIC,
-            &mut device.P0,
-            23,
-            22,
+            &mut scl,
+            &mut sda,
             twim::Frequency::K400,
         );
         let mut sht4x = sht4x::SHT4X::new(&mut twim, &mut timer, 0x44);
@@ -74,8 +177,10 @@ fn main() -> ! {
         sht4x.wait_for_serial().unwrap()
     };
 
+    // the RTC runs continuously as the time base of the node
     let mut rtc = rtc::Rtc::new(&mut device.RTC0, &mut core.NVIC);
-    rtc.set_prescaler(3276); // 0.1 s
+    rtc.set_prescaler(RTC_PRESCALER).unwrap();
+    rtc.start_monotonic();
 
     // set delay time based on whether debug or production build is run
     // if cfg!(debug_assertions) {
@@ -83,27 +188,105 @@ fn main() -> ! {
     // } else {
     //     rtc.set_compare(600); // production interval: 1 min
     // }
-    rtc.set_compare(600); // production interval: 1 min
+    let mut interval_ticks = rtc.micros_to_ticks(DEFAULT_INTERVAL_S * 1_000_000); // production interval: 1 min
+
+    // load the network parameters; the node sends with its device id until it has joined a network
+    let mut nvmc = nvmc::Nvmc::new(device.NVMC);
+    let mut network_config = network::NetworkConfig::load(&nvmc);
+
+    if let Some(config) = &network_config {
+        interval_ticks = rtc.micros_to_ticks(config.interval as u64 * 1_000_000);
+    }
+
+    let mut measurement_log = log::Log::load(&mut nvmc).unwrap();
+    let mut ccm = hal::ccm::Ccm::init(device.CCM, device.AAR, hal::ccm::DataRate::_2Mbit);
+    let mut rng = hal::rng::Rng::new(device.RNG);
 
-    // initialize index
-    let mut index = 0u32;
+    // continue the indices of the samples in the log, so that they are not reused after a restart
+    let mut index = match FLASH_LOG {
+        true => measurement_log.next_index(),
+        false => 0u32,
+    };
+    let sample_ticks = rtc.micros_to_ticks(SAMPLE_INTERVAL_S * 1_000_000);
+    let mut next_transmission = rtc.ticks() + interval_ticks;
+    let mut next_sample = rtc.ticks() + sample_ticks;
+    let mut samples = [Sample {
+        time: 0,
+        records: [0; AGGREGATE_RECORDS_LEN + DIAGNOSTICS_LEN],
+        len: 0,
+        reported: false,
+        log_address: None,
+    }; BATCH_LEN];
+    let mut sample_count = 0;
+    let mut temperature_statistics = aggregate::Statistics::new();
+    let mut humidity_statistics = aggregate::Statistics::new();
+    let mut report_filter = report::ReportFilter::new(
+        TEMPERATURE_THRESHOLD,
+        HUMIDITY_THRESHOLD,
+        HEARTBEAT_S * 1_000_000,
+    );
+    let mut time_sync = sync::TimeSync::new();
+    let mut cycles_since_sync = SYNC_CYCLES;
+    // time the node wakes up earlier than planned to hit its slot (µs)
+    let mut slot_correction = 0i64;
 
     loop {
-        // wait
-        rtc.start();
-        rtc.wait();
+        // wait for the slot of the node or, without a slot, for the next interval
+        let slot = match &network_config {
+            Some(config) => time_sync
+                .network_time(rtc.micros())
+                .map(|network| {
+                    network::next_slot(network + SLOT_LEAD_MUS, config.slot_offset, config.interval)
+                })
+                .and_then(|slot| time_sync.local_time(slot)),
+            None => None,
+        };
+
+        let transmission = match slot {
+            Some(slot) => {
+                let wake_up = slot.saturating_sub(SLOT_LEAD_MUS) as i64 - slot_correction;
+
+                rtc.micros_to_ticks(wake_up.max(0) as u64)
+            }
+            None => next_transmission,
+        };
+
+        // take samples in between transmissions if the node sends batches or statistics (only with
+        // a short address); the last sample is taken right before the transmission
+        let sampling = network_config.is_some() && sample_ticks < interval_ticks;
+        let aggregating = sampling && AGGREGATE;
+        let transmit = !sampling
+            || (!aggregating && sample_count + 1 >= BATCH_LEN)
+            || transmission <= next_sample;
+        let mut next_alarm = match transmit {
+            true => transmission,
+            false => next_sample,
+        };
 
-        if conf_version_major == 1 && conf_version_minor == 1 {
+        if !rtc.set_alarm(next_alarm) {
+            // the last cycle took longer than the interval
+            next_alarm = rtc.ticks()
+                + match transmit {
+                    true => interval_ticks,
+                    false => sample_ticks,
+                };
+            rtc.set_alarm(next_alarm);
+        }
+
+        rtc.wait_alarm();
+        next_sample = next_alarm + sample_ticks;
+
+        if let Some(snooze) = snooze.as_mut() {
             // disable snooze mode (pull pin 19 high)
-            device.P0.outset.write(|w| w.pin19().set());
+            snooze.set_high();
         }
 
         // get battery voltage
-        let mut saadc = saadc::Saadc::new(device.SAADC, device.P0, 3);
+        let mut saadc = saadc::Saadc::new(device.SAADC, battery_pin).unwrap();
         let battery_voltage = saadc.getValue();
         let tmp = saadc.free();
         device.SAADC = tmp.0;
-        device.P0 = tmp.1;
+        battery_pin = tmp.1;
 
         // if battery voltage is lower 1.1 V and we are not running in debug mode,
         // go into a sleep loop
@@ -114,19 +297,25 @@ fn main() -> ! {
         //     }
         // }
 
-        let measurement = {
-            let mut timer = timer::Timer::new(&mut device.TIMER0, &mut core.NVIC);
-            let mut twim = twim::Twim::new(
-                &mut device.TWIM0,
-                &mut core.NVIC,
-                &mut device.P0,
-                23,
-                22,
-                twim::Frequency::K400,
-            );
-            let mut sht4x = sht4x::SHT4X::new(&mut twim, &mut timer, 0x44);
-            sht4x.start_measurement().unwrap();
-            sht4x.wait_for_measurement().unwrap()
+        // a failing sensor (e.g. a bus held low after a brown-out) costs the sample instead of
+        // stopping the node
+        let measurement = match measure(
+            &mut device.TWIM0,
+            &mut device.TIMER0,
+            &mut device.TIMER1,
+            &mut core.NVIC,
+            &mut scl,
+            &mut sda,
+        ) {
+            Ok(measurement) => measurement,
+            Err(_) => {
+                if let Some(snooze) = snooze.as_mut() {
+                    // enable snooze mode (pull pin 19 low)
+                    snooze.set_low();
+                }
+
+                continue;
+            }
         };
 
         // let mag = {
@@ -149,48 +338,826 @@ fn main() -> ! {
         //     mmc.wait_for_magnetic_measurement().unwrap()
         // };
 
-        // create package
-        let mut package: [u8; 54] = [0; 54];
-
-        package[0..2].copy_from_slice(&5u16.to_le_bytes()[..]);
-        package[2..10].copy_from_slice(&device_id.to_le_bytes()[..]);
-        package[10..14].copy_from_slice(&part_id.to_le_bytes()[..]);
-        package[14..18].copy_from_slice(&index.to_le_bytes()[..]);
-        package[18..22].copy_from_slice(&0u32.to_le_bytes()[..]);
-        package[22..24].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[24..26].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[26..28].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[28..32].copy_from_slice(&0u32.to_le_bytes()[..]);
-        package[32..34].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[34..36].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[36..38].copy_from_slice(&0i16.to_le_bytes()[..]);
-        package[38..42].copy_from_slice(&serial.to_le_bytes()[..]);
-        package[42..46].copy_from_slice(&measurement.temperature.to_le_bytes()[..]);
-        package[46..50].copy_from_slice(&measurement.humidity.to_le_bytes()[..]);
-        package[50..54].copy_from_slice(&battery_voltage.to_le_bytes()[..]);
+        let tx_power = power_control.power();
+        // absolute time of the measurement if the gateway provides it
+        let time = match (
+            time_sync.is_absolute(),
+            time_sync.network_time(rtc.micros()),
+        ) {
+            (true, Some(time)) => Some((time / 1_000_000) as u32),
+            _ => None,
+        };
+        let statistics = match aggregating {
+            true => {
+                temperature_statistics.add(measurement.temperature);
+                humidity_statistics.add(measurement.humidity);
+
+                if !transmit {
+                    // the index counts the reports
+                    if let Some(snooze) = snooze.as_mut() {
+                        // enable snooze mode (pull pin 19 low)
+                        snooze.set_low();
+                    }
 
-        // increment index
-        index += 1;
+                    continue;
+                }
+
+                Some((&temperature_statistics, &humidity_statistics))
+            }
+            false => None,
+        };
+        // changes of the mean are reported when sending statistics
+        let (temperature, humidity) = match statistics {
+            Some((temperature, humidity)) => (
+                temperature.mean().unwrap_or(measurement.temperature),
+                humidity.mean().unwrap_or(measurement.humidity),
+            ),
+            None => (measurement.temperature, measurement.humidity),
+        };
+        // nodes that have not joined the network report at every interval to try joining
+        let reason = match (REPORT_ON_CHANGE, &network_config) {
+            (true, Some(_)) => report_filter.check(rtc.micros(), temperature, humidity),
+            _ => Some(report::Reason::Interval),
+        };
+        let sample = &mut samples[sample_count];
+
+        sample.time = rtc.micros();
+        sample.len = write_records(
+            &mut sample.records,
+            serial,
+            &measurement,
+            statistics,
+            battery_voltage,
+            tx_power,
+            time,
+            reason,
+        );
+        sample.reported = reason.is_some();
+        sample_count += 1;
+
+        if !transmit {
+            index = index.wrapping_add(1);
+
+            if let Some(snooze) = snooze.as_mut() {
+                // enable snooze mode (pull pin 19 low)
+                snooze.set_low();
+            }
+
+            continue;
+        }
+
+        cycles_since_sync += 1;
+        next_transmission = next_alarm + interval_ticks;
+
+        if !samples[..sample_count].iter().any(|sample| sample.reported) {
+            // nothing to report; the index keeps counting, so that the gateway can tell suppressed
+            // samples from lost ones
+            index = index.wrapping_add(1);
+            sample_count = 0;
+            temperature_statistics.reset();
+            humidity_statistics.reset();
+
+            if let Some(snooze) = snooze.as_mut() {
+                // enable snooze mode (pull pin 19 low)
+                snooze.set_low();
+            }
+
+            continue;
+        }
 
-        // send package
         let clock_hf_active = clock.start_hfclk();
+        // index of the first sample
+        let base_index = index.wrapping_sub(sample_count as u32 - 1);
 
-        radio.init_transmission();
-        let package_wrapper: [&[u8]; 1] = [&package];
-        radio.start_transmission(&package_wrapper);
+        radio.set_tx_power(tx_power);
 
-        let peri = unsafe { pac::Peripherals::steal() };
+        let ack = match &network_config {
+            Some(config) => {
+                if FLASH_LOG {
+                    // keep the samples until they are acknowledged and report the state of the log
+                    // with the newest sample
+                    for (cntr, sample) in samples[..sample_count].iter_mut().enumerate() {
+                        sample.log_address = measurement_log
+                            .append(
+                                &mut nvmc,
+                                base_index.wrapping_add(cntr as u32),
+                                (sample.time / 1_000_000) as u32,
+                                &sample.records[..sample.len],
+                            )
+                            .ok();
+                    }
 
-        while peri.RADIO.events_end.read().events_end().is_not_generated() {}
+                    if let Ok(status) = measurement_log.status(&nvmc) {
+                        let sample = &mut samples[sample_count - 1];
+
+                        sample.len = write_diagnostics(&mut sample.records, sample.len, &status);
+                    }
+                }
+
+                // send package with the short address; several samples are sent as a batch
+                let mut plain = [0u8; PLAIN_LEN];
+                let (package_type, plain_len) = match sample_count {
+                    1 => {
+                        let sample = &samples[0];
+
+                        plain[0..4].copy_from_slice(&index.to_le_bytes()[..]);
+                        plain[4..4 + sample.len].copy_from_slice(&sample.records[..sample.len]);
+
+                        (packet::TYPE_SHORT_DATA, 4 + sample.len)
+                    }
+                    _ => {
+                        let now = rtc.micros();
+                        let mut writer = batch::Writer::new(&mut plain, base_index).unwrap();
+
+                        for sample in &samples[..sample_count] {
+                            let age = (now.saturating_sub(sample.time) + 500_000) / 1_000_000;
+
+                            writer
+                                .push(
+                                    Some(age.min(u32::MAX as u64) as u32),
+                                    &sample.records[..sample.len],
+                                )
+                                .unwrap();
+                        }
+
+                        (packet::TYPE_SHORT_BATCH, writer.len())
+                    }
+                };
+
+                send_short(
+                    &mut radio,
+                    &mut device.TIMER0,
+                    &mut core.NVIC,
+                    &mut ccm,
+                    &mut rng,
+                    config,
+                    package_type,
+                    &plain[..plain_len],
+                )
+            }
+            None => {
+                // create package
+                let mut package = [0u8; packet::SENSOR_DATA_HEADER_LEN + AGGREGATE_RECORDS_LEN];
+                let sample = &samples[0];
+                let package_len = packet::SENSOR_DATA_HEADER_LEN + sample.len;
+
+                package[0..2].copy_from_slice(
+                    &(packet::TYPE_SENSOR_DATA | packet::FLAG_ACK_REQUEST).to_le_bytes()[..],
+                );
+                package[2..10].copy_from_slice(&device_id.to_le_bytes()[..]);
+                package[10..14].copy_from_slice(&part_id.to_le_bytes()[..]);
+                package[14..18].copy_from_slice(&index.to_le_bytes()[..]);
+                package[packet::SENSOR_DATA_HEADER_LEN..package_len]
+                    .copy_from_slice(&sample.records[..sample.len]);
+
+                // send package
+                send(
+                    &mut radio,
+                    &mut device.TIMER0,
+                    &mut core.NVIC,
+                    &package[..package_len],
+                );
+                let ack = receive_ack(
+                    &mut radio,
+                    &mut device.TIMER0,
+                    &mut core.NVIC,
+                    device_id,
+                    index,
+                );
+
+                // try to join the network
+                network_config = join(
+                    &mut radio,
+                    &mut device.TIMER0,
+                    &mut core.NVIC,
+                    &mut ccm,
+                    &mut rng,
+                    device_id,
+                    part_id,
+                );
+
+                if let Some(config) = &network_config {
+                    config.store(&mut nvmc).unwrap();
+                    interval_ticks = rtc.micros_to_ticks(config.interval as u64 * 1_000_000);
+                }
+
+                ack
+            }
+        };
+
+        // adjust the transmit power and the wake-up time
+        match ack {
+            Some(ack) => {
+                power_control.on_ack(ack.rssi);
+
+                if slot.is_some() {
+                    slot_correction = (slot_correction + ack.timing as i64 / 2)
+                        .max(-MAX_SLOT_CORRECTION_MUS)
+                        .min(MAX_SLOT_CORRECTION_MUS);
+                }
+            }
+            None => power_control.on_missing_ack(),
+        }
+
+        // the link to the gateway works; remove the acknowledged samples from the log and send
+        // the samples the gateway missed
+        if let (true, Some(config), Some(_)) = (FLASH_LOG, &network_config, &ack) {
+            for sample in &samples[..sample_count] {
+                if let Some(address) = sample.log_address {
+                    measurement_log.acknowledge(&mut nvmc, address).ok();
+                }
+            }
+
+            for _ in 0..BACKFILL_BATCHES {
+                let sent = backfill(
+                    &mut radio,
+                    &mut device.TIMER0,
+                    &mut core.NVIC,
+                    &mut ccm,
+                    &mut rng,
+                    &mut nvmc,
+                    &mut measurement_log,
+                    config,
+                    rtc.micros(),
+                );
+
+                if !sent {
+                    break;
+                }
+            }
+        }
 
-        radio.event_reset_all();
         radio.set_enabled(false);
 
+        // increment index
+        index = index.wrapping_add(1);
+        sample_count = 0;
+        temperature_statistics.reset();
+        humidity_statistics.reset();
+
         clock = clock_hf_active.stop_hfclk();
 
-        if conf_version_major == 1 && conf_version_minor == 1 {
+        if let Some(snooze) = snooze.as_mut() {
             // enable snooze mode (pull pin 19 low)
-            device.P0.outclr.write(|w| w.pin19().clear());
+            snooze.set_low();
+        }
+
+        // catch a time beacon now and then to discipline the clock
+        if cycles_since_sync >= SYNC_CYCLES {
+            let timeout = match time_sync.next_beacon(rtc.micros()) {
+                Some(beacon) => {
+                    let uncertainty = time_sync.uncertainty(beacon);
+
+                    // sleep until shortly before the beacon is expected
+                    if rtc.set_alarm(rtc.micros_to_ticks(beacon.saturating_sub(uncertainty))) {
+                        rtc.wait_alarm();
+                    }
+
+                    // relays forward the beacon with a delay
+                    (2 * uncertainty) as u32 + relay_delay(1)
+                }
+                None => SEARCH_TIMEOUT_MUS,
+            };
+            let clock_hf_active = clock.start_hfclk();
+            let beacon = receive(
+                &mut radio,
+                &mut device.TIMER0,
+                &mut core.NVIC,
+                timeout,
+                |payload| {
+                    packet::TimeBeacon::from_bytes(packet::unwrap_relayed(payload)).map(|beacon| {
+                        let air_time = packet::beacon_air_time_mus(payload.len());
+
+                        (beacon, rtc.micros().saturating_sub(air_time))
+                    })
+                },
+            );
+
+            radio.set_enabled(false);
+            clock = clock_hf_active.stop_hfclk();
+
+            match beacon {
+                Some((beacon, local)) => {
+                    time_sync.on_beacon(
+                        local,
+                        beacon.time,
+                        beacon.interval as u64 * 1_000_000,
+                        beacon.absolute,
+                    );
+                    cycles_since_sync = 0;
+                }
+                // searching is expensive; wait for the next round
+                None if !time_sync.is_synchronised() => cycles_since_sync = 0,
+                // the beacon was missed; try again in the next cycle
+                None => {}
+            }
+        }
+    }
+}
+
+/// Write the measurements as records in the self-describing format.
+///
+/// With statistics of temperature and humidity, these are written instead of the measurement.
+/// Returns the length of the payload.
+#[allow(clippy::too_many_arguments)]
+fn write_records(
+    buffer: &mut [u8],
+    serial: u32,
+    measurement: &Measurement,
+    statistics: Option<(&aggregate::Statistics, &aggregate::Statistics)>,
+    battery_voltage: f32,
+    tx_power: radio::TxPower,
+    time: Option<u32>,
+    reason: Option<report::Reason>,
+) -> usize {
+    let mut writer = tlv::Writer::new(buffer).unwrap();
+    let mut push = |record: tlv::Record| writer.push(&record).unwrap();
+
+    push(tlv::Record::new(
+        tlv::KIND_SENSOR_ID,
+        tlv::UNIT_NONE,
+        tlv::Value::U32(serial),
+    ));
+
+    match statistics {
+        Some((temperature, humidity)) => {
+            let values = [
+                (tlv::STATISTIC_MIN, temperature.min(), humidity.min()),
+                (tlv::STATISTIC_MAX, temperature.max(), humidity.max()),
+                (tlv::STATISTIC_MEAN, temperature.mean(), humidity.mean()),
+            ];
+
+            for (statistic, temperature, humidity) in values.iter() {
+                if let Some(temperature) = temperature {
+                    push(temperature_record(*temperature).with_statistic(*statistic));
+                }
+
+                if let Some(humidity) = humidity {
+                    push(humidity_record(*humidity).with_statistic(*statistic));
+                }
+            }
+
+            push(tlv::Record::new(
+                tlv::KIND_SAMPLE_COUNT,
+                tlv::UNIT_NONE,
+                tlv::Value::U16(temperature.count().min(u16::MAX as u32) as u16),
+            ));
+        }
+        None => {
+            push(temperature_record(measurement.temperature));
+            push(humidity_record(measurement.humidity));
+        }
+    }
+
+    push(match COMPACT_ENCODING {
+        true => tlv::Record::battery_voltage(battery_voltage),
+        false => tlv::Record::new(
+            tlv::KIND_BATTERY_VOLTAGE,
+            tlv::UNIT_VOLT,
+            tlv::Value::F32(battery_voltage),
+        ),
+    });
+    push(tlv::Record::new(
+        tlv::KIND_TX_POWER,
+        tlv::UNIT_DBM,
+        tlv::Value::I8(tx_power.dbm()),
+    ));
+
+    if let Some(time) = time {
+        push(tlv::Record::new(
+            tlv::KIND_TIME,
+            tlv::UNIT_SECOND,
+            tlv::Value::U32(time),
+        ));
+    }
+
+    if let Some(reason) = reason {
+        push(tlv::Record::new(
+            tlv::KIND_REPORT_REASON,
+            tlv::UNIT_NONE,
+            tlv::Value::U8(reason as u8),
+        ));
+    }
+
+    writer.len()
+}
+
+fn temperature_record(celsius: f32) -> tlv::Record {
+    match COMPACT_ENCODING {
+        true => tlv::Record::temperature(celsius),
+        false => tlv::Record::new(
+            tlv::KIND_TEMPERATURE,
+            tlv::UNIT_DEGREE_CELSIUS,
+            tlv::Value::F32(celsius),
+        ),
+    }
+}
+
+fn humidity_record(percent: f32) -> tlv::Record {
+    match COMPACT_ENCODING {
+        true => tlv::Record::humidity(percent),
+        false => tlv::Record::new(
+            tlv::KIND_HUMIDITY,
+            tlv::UNIT_PERCENT_RH,
+            tlv::Value::F32(percent),
+        ),
+    }
+}
+
+/// Measure temperature and humidity.
+///
+/// If the measurement fails, the bus is recovered and the measurement is repeated once.
+fn measure(
+    twim0: &mut pac::TWIM0,
+    timer0: &mut pac::TIMER0,
+    timer1: &mut pac::TIMER1,
+    nvic: &mut pac::NVIC,
+    scl: &mut p0::Pin<p0::OpenDrain>,
+    sda: &mut p0::Pin<p0::OpenDrain>,
+) -> Result<Measurement, sht4x::Sht4xError<twim::Error>> {
+    if let Ok(measurement) = measure_once(twim0, timer0, timer1, nvic, scl, sda) {
+        return Ok(measurement);
+    }
+
+    twim::Twim::new(twim0, timer1, nvic, scl, sda, twim::Frequency::K400)
+        .recover()
+        .map_err(sht4x::Sht4xError::EmbeddedError)?;
+
+    measure_once(twim0, timer0, timer1, nvic, scl, sda)
+}
+
+fn measure_once(
+    twim0: &mut pac::TWIM0,
+    timer0: &mut pac::TIMER0,
+    timer1: &mut pac::TIMER1,
+    nvic: &mut pac::NVIC,
+    scl: &mut p0::Pin<p0::OpenDrain>,
+    sda: &mut p0::Pin<p0::OpenDrain>,
+) -> Result<Measurement, sht4x::Sht4xError<twim::Error>> {
+    let mut timer = timer::Timer::new(timer0, nvic);
+    let mut twim = twim::Twim::new(twim0, timer1, nvic, scl, sda, twim::Frequency::K400);
+    let mut sht4x = sht4x::SHT4X::new(&mut twim, &mut timer, 0x44);
+
+    sht4x.start_measurement()?;
+    sht4x.wait_for_measurement()
+}
+
+/// Append the state of the flash log to the records of a sample.
+///
+/// Returns the length of the payload.
+fn write_diagnostics(buffer: &mut [u8], len: usize, status: &log::Status) -> usize {
+    let mut writer = tlv::Writer::append(buffer, len).unwrap();
+    let records = [
+        tlv::Record::new(
+            tlv::KIND_LOG_PENDING,
+            tlv::UNIT_NONE,
+            tlv::Value::U16(status.pending.min(u16::MAX as u32) as u16),
+        ),
+        tlv::Record::new(
+            tlv::KIND_LOG_FREE,
+            tlv::UNIT_NONE,
+            tlv::Value::U16(status.free.min(u16::MAX as u32) as u16),
+        ),
+        tlv::Record::new(
+            tlv::KIND_FLASH_ERASES,
+            tlv::UNIT_NONE,
+            tlv::Value::U32(status.erases),
+        ),
+    ];
+
+    for record in records.iter() {
+        writer.push(record).unwrap();
+    }
+
+    writer.len()
+}
+
+/// Send the oldest samples of the flash log that have not been acknowledged as a batch.
+///
+/// Returns `true` if samples were sent and acknowledged.
+///
+/// # Arguments
+///
+/// * `now` - local time (µs)
+///
+#[allow(clippy::too_many_arguments)]
+fn backfill(
+    radio: &mut radio::Radio,
+    timer0: &mut pac::TIMER0,
+    nvic: &mut pac::NVIC,
+    ccm: &mut hal::ccm::Ccm,
+    rng: &mut hal::rng::Rng,
+    nvmc: &mut nvmc::Nvmc,
+    measurement_log: &mut log::Log,
+    config: &network::NetworkConfig,
+    now: u64,
+) -> bool {
+    let mut entries = [log::Entry::new(); BATCH_LEN];
+    let count = match measurement_log.read_pending(nvmc, &mut entries) {
+        Ok(count) if count > 0 => count,
+        _ => return false,
+    };
+    let mut plain = [0u8; PLAIN_LEN];
+    let mut writer = batch::Writer::new(&mut plain, entries[0].index).unwrap();
+    let mut batched = 0;
+
+    for entry in &entries[..count] {
+        // the age of samples taken before the last start of the node is unknown
+        let age = match entry.current {
+            true => Some(
+                (now / 1_000_000)
+                    .saturating_sub(entry.time as u64)
+                    .min(u32::MAX as u64) as u32,
+            ),
+            false => None,
+        };
+
+        if writer.push(age, &entry.records[..entry.len]).is_err() {
+            break;
         }
+
+        batched += 1;
+    }
+
+    let plain_len = writer.len();
+    let ack = send_short(
+        radio,
+        timer0,
+        nvic,
+        ccm,
+        rng,
+        config,
+        packet::TYPE_SHORT_BATCH,
+        &plain[..plain_len],
+    );
+
+    if ack.is_none() {
+        return false;
+    }
+
+    for entry in &entries[..batched] {
+        measurement_log.acknowledge(nvmc, entry.address).ok();
+    }
+
+    true
+}
+
+/// Encrypt a payload with the session key, send it with the short address and wait for the
+/// acknowledgement.
+///
+/// The plain text starts with the index acknowledged by the gateway.
+#[allow(clippy::too_many_arguments)]
+fn send_short(
+    radio: &mut radio::Radio,
+    timer0: &mut pac::TIMER0,
+    nvic: &mut pac::NVIC,
+    ccm: &mut hal::ccm::Ccm,
+    rng: &mut hal::rng::Rng,
+    config: &network::NetworkConfig,
+    package_type: u16,
+    plain: &[u8],
+) -> Option<packet::Ack> {
+    let mut iv = [0u8; 8];
+    let mut package = [0u8; packet::SHORT_HEADER_LEN + PLAIN_LEN + crypto::MIC_LEN];
+    let index = u32::from_le_bytes([plain[0], plain[1], plain[2], plain[3]]);
+
+    rng.random(&mut iv);
+
+    package[0..2].copy_from_slice(
+        &(package_type | packet::FLAG_ENCRYPTED | packet::FLAG_ACK_REQUEST).to_le_bytes()[..],
+    );
+    package[2..4].copy_from_slice(&config.short_address.to_le_bytes()[..]);
+    package[4..12].copy_from_slice(&iv);
+    let cipher_len = crypto::encrypt(
+        ccm,
+        config.session_key,
+        iv,
+        plain,
+        &mut package[packet::SHORT_HEADER_LEN..],
+    )
+    .unwrap();
+
+    send(
+        radio,
+        timer0,
+        nvic,
+        &package[..packet::SHORT_HEADER_LEN + cipher_len],
+    );
+    receive_ack(radio, timer0, nvic, config.short_address as u64, index)
+}
+
+/// Transmit a package and wait until the transmission is complete.
+///
+/// A transmission that has not completed in time (e.g. as the radio was not disabled before) is
+/// stopped.
+fn send(radio: &mut radio::Radio, timer0: &mut pac::TIMER0, nvic: &mut pac::NVIC, package: &[u8]) {
+    let package_wrapper: [&[u8]; 1] = [package];
+    let mut timer = timer::Timer::new(timer0, nvic);
+
+    radio.init_transmission();
+    radio.start_transmission(&package_wrapper).unwrap();
+    timer.set_timeout_mus(TX_TIMEOUT_MUS);
+    timer.start();
+
+    while !radio.event_disabled() && !timer.is_expired() {}
+
+    timer.stop();
+
+    if !radio.event_disabled() {
+        radio.disable();
+
+        while !radio.event_disabled() {}
+    }
+
+    radio.event_reset_all();
+}
+
+/// Listen for the acknowledgement of a package.
+///
+/// Returns `None` if no acknowledgement arrived in time; the next acknowledgement is then awaited
+/// as long as it takes through the most relays, in case the node can only reach the gateway
+/// through relays now.
+fn receive_ack(
+    radio: &mut radio::Radio,
+    timer0: &mut pac::TIMER0,
+    nvic: &mut pac::NVIC,
+    device_id: u64,
+    index: u32,
+) -> Option<packet::Ack> {
+    let ack = receive(
+        radio,
+        timer0,
+        nvic,
+        ACK_TIMEOUT_MUS + relay_delay(2),
+        |payload| match packet::Ack::from_bytes(packet::unwrap_relayed(payload)) {
+            Some(ack) if ack.device_id == device_id && ack.index == index => Some(ack),
+            _ => None,
+        },
+    );
+
+    if ack.is_none() {
+        HOPS.store(packet::MAX_HOPS, Ordering::Relaxed);
+    }
+
+    ack
+}
+
+/// Longest delay the relays between the gateway and the node add (µs).
+///
+/// # Arguments
+///
+/// * `ways` - number of times the relays are passed (2 for a request and its answer)
+///
+fn relay_delay(ways: u32) -> u32 {
+    ways * HOPS.load(Ordering::Relaxed) as u32 * packet::MAX_RELAY_DELAY_MUS
+}
+
+/// Send a join request and wait for the parameters assigned by the gateway.
+fn join(
+    radio: &mut radio::Radio,
+    timer0: &mut pac::TIMER0,
+    nvic: &mut pac::NVIC,
+    ccm: &mut hal::ccm::Ccm,
+    rng: &mut hal::rng::Rng,
+    device_id: u64,
+    part_id: u32,
+) -> Option<network::NetworkConfig> {
+    let request = packet::JoinRequest {
+        device_id,
+        part_id,
+        nonce: rng.random_u32(),
+    };
+    let mut iv = [0u8; 8];
+    let mut package = [0u8; 10 + packet::JOIN_REQUEST_LEN + crypto::MIC_LEN];
+
+    rng.random(&mut iv);
+
+    // the request is encrypted with the network key, so that the gateway can authenticate it
+    package[0..2]
+        .copy_from_slice(&(packet::TYPE_JOIN_REQUEST | packet::FLAG_ENCRYPTED).to_le_bytes()[..]);
+    package[2..10].copy_from_slice(&iv);
+    let cipher_len = crypto::encrypt(
+        ccm,
+        get_pipe_key(GATEWAY_PIPE),
+        iv,
+        &request.to_bytes(),
+        &mut package[10..],
+    )?;
+
+    send(radio, timer0, nvic, &package[..10 + cipher_len]);
+    // the node does not know the relays on the way to the gateway yet
+    HOPS.store(packet::MAX_HOPS, Ordering::Relaxed);
+
+    let timeout = JOIN_TIMEOUT_MUS + relay_delay(2);
+
+    receive(radio, timer0, nvic, timeout, |payload| {
+        let payload = packet::unwrap_relayed(payload);
+        let package_type = packet::packet_type(payload)?;
+
+        if package_type != packet::TYPE_JOIN_ACCEPT | packet::FLAG_ENCRYPTED || payload.len() < 10 {
+            return None;
+        }
+
+        let mut iv = [0u8; 8];
+        let mut plain = [0u8; packet::JOIN_ACCEPT_LEN];
+
+        copy_into_array(&payload[2..10], &mut iv);
+
+        if crypto::decrypt(
+            ccm,
+            get_pipe_key(GATEWAY_PIPE),
+            iv,
+            &payload[10..],
+            &mut plain,
+        )? != packet::JOIN_ACCEPT_LEN
+        {
+            return None;
+        }
+
+        match packet::JoinAccept::from_bytes(&plain) {
+            Some(accept) if accept.device_id == device_id && accept.nonce == request.nonce => {
+                Some(network::NetworkConfig {
+                    short_address: accept.short_address,
+                    session_key: accept.session_key,
+                    interval: accept.interval,
+                    slot_offset: accept.slot_offset,
+                })
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Listen for a package until `accept` returns a value or the timeout expires.
+///
+/// `accept` gets packets forwarded by relays as received; the relays the accepted packet passed
+/// are remembered in `HOPS`.
+fn receive<T>(
+    radio: &mut radio::Radio,
+    timer0: &mut pac::TIMER0,
+    nvic: &mut pac::NVIC,
+    timeout: u32,
+    mut accept: impl FnMut(&[u8]) -> Option<T>,
+) -> Option<T> {
+    let mut timer = timer::Timer::new(timer0, nvic);
+    let mut res = None;
+
+    radio.init_reception();
+    radio.start_reception().unwrap();
+    timer.set_timeout_mus(timeout);
+    timer.start();
+
+    while res.is_none() && !timer.is_expired() {
+        if !radio.event_disabled() {
+            continue;
+        }
+
+        radio.event_reset_all();
+        radio.complete_reception(0);
+
+        if let Some(token) = radio.take_frame() {
+            let frame = radio.frame(&token);
+
+            if frame.crc_ok {
+                res = accept(frame.payload);
+
+                if res.is_some() {
+                    let hops = packet::Relayed::from_bytes(frame.payload)
+                        .map_or(0, |relayed| relayed.hops);
+
+                    HOPS.store(hops, Ordering::Relaxed);
+                }
+            }
+
+            radio.release_frame(token);
+        }
+
+        if res.is_none() {
+            radio.start_reception().unwrap();
+        }
+    }
+
+    timer.stop();
+
+    if res.is_none() {
+        stop_reception(radio);
+    }
+
+    res
+}
+
+/// Stop a reception in progress, as the radio ignores TXEN while receiving, and release the frames
+/// received meanwhile.
+fn stop_reception(radio: &mut radio::Radio) {
+    // the reception may also have ended just before
+    radio.disable();
+
+    while !radio.event_disabled() {}
+
+    radio.event_reset_all();
+    radio.complete_reception(0);
+
+    while let Some(token) = radio.take_frame() {
+        radio.release_frame(token);
     }
 }
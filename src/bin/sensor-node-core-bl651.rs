@@ -77,14 +77,18 @@ fn main() -> ! {
     //     p0::Sense::Disabled,
     // );
 
+    // SCL P0.25, SDA P0.26
+    let mut scl = p0::Pin::take(25).unwrap().into_open_drain(p0::Pull::PullUp);
+    let mut sda = p0::Pin::take(26).unwrap().into_open_drain(p0::Pull::PullUp);
+
     let serial = {
         let mut timer = timer::Timer::new(&mut device.TIMER0, &mut core.NVIC);
         let mut twim = twim::Twim::new(
             &mut device.TWIM0,
+            &mut device.TIMER1,
             &mut core.NVIC,
-            &mut device.P0,
-            25,
-            26,
+            &mut scl,
+            &mut sda,
             twim::Frequency::K100,
         );
         let mut sht4x = sht4x::SHT4X::new(&mut twim, &mut timer, 0x44);
@@ -95,9 +99,9 @@ fn main() -> ! {
     };
 
     let mut rtc = rtc::Rtc::new(&mut device.RTC0, &mut core.NVIC);
-    rtc.set_prescaler(3276); // 0.1 s
-                             // rtc.set_compare(30); // 3 s
-    rtc.set_compare(600); // 1 min
+    rtc.set_prescaler(3276).unwrap(); // 0.1 s
+                                      // rtc.set_compare(30); // 3 s
+    rtc.set_compare(600).unwrap(); // 1 min
 
     // initialize index
     let mut index = 0u32;
@@ -112,10 +116,10 @@ fn main() -> ! {
             let mut timer = timer::Timer::new(&mut device.TIMER0, &mut core.NVIC);
             let mut twim = twim::Twim::new(
                 &mut device.TWIM0,
+                &mut device.TIMER1,
                 &mut core.NVIC,
-                &mut device.P0,
-                25,
-                26,
+                &mut scl,
+                &mut sda,
                 twim::Frequency::K400,
             );
             let mut sht4x = sht4x::SHT4X::new(&mut twim, &mut timer, 0x44);
@@ -143,7 +147,7 @@ fn main() -> ! {
 
         radio.init_transmission();
         let package_wrapper: [&[u8]; 1] = [&package];
-        radio.start_transmission(&package_wrapper);
+        radio.start_transmission(&package_wrapper).unwrap();
 
         let peri = unsafe { pac::Peripherals::steal() };
 
@@ -0,0 +1,165 @@
+//! Running statistics over the samples of a reporting window
+//!
+//! The module does not depend on the rest of the crate, so that its tests run on the host with
+//! `make test`.
+
+#[derive(Clone, Copy)]
+pub struct Statistics {
+    count: u32,
+    min: f32,
+    max: f32,
+    mean: f32,
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics {
+            count: 0,
+            min: 0.0,
+            max: 0.0,
+            mean: 0.0,
+        }
+    }
+
+    /// Add a sample; invalid samples (NaN) are ignored.
+    pub fn add(&mut self, value: f32) {
+        if value.is_nan() {
+            return;
+        }
+
+        if self.count == 0 {
+            self.count = 1;
+            self.min = value;
+            self.max = value;
+            self.mean = value;
+
+            return;
+        }
+
+        self.count = self.count.saturating_add(1);
+
+        if value < self.min {
+            self.min = value;
+        }
+
+        if value > self.max {
+            self.max = value;
+        }
+
+        // the running mean does not lose precision with the number of samples like a sum would
+        self.mean += (value - self.mean) / self.count as f32;
+    }
+
+    /// Start a new window.
+    pub fn reset(&mut self) {
+        *self = Statistics::new();
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<f32> {
+        self.value(self.min)
+    }
+
+    pub fn max(&self) -> Option<f32> {
+        self.value(self.max)
+    }
+
+    pub fn mean(&self) -> Option<f32> {
+        self.value(self.mean)
+    }
+
+    fn value(&self, value: f32) -> Option<f32> {
+        match self.count {
+            0 => None,
+            _ => Some(value),
+        }
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let statistics = Statistics::new();
+
+        assert_eq!(statistics.count(), 0);
+        assert_eq!(statistics.min(), None);
+        assert_eq!(statistics.max(), None);
+        assert_eq!(statistics.mean(), None);
+    }
+
+    #[test]
+    fn add() {
+        let mut statistics = Statistics::new();
+
+        for value in [21.5, 19.0, 23.5, 20.0].iter() {
+            statistics.add(*value);
+        }
+
+        assert_eq!(statistics.count(), 4);
+        assert_eq!(statistics.min(), Some(19.0));
+        assert_eq!(statistics.max(), Some(23.5));
+        assert_eq!(statistics.mean(), Some(21.0));
+    }
+
+    #[test]
+    fn single_sample() {
+        let mut statistics = Statistics::new();
+
+        statistics.add(-3.25);
+
+        assert_eq!(statistics.count(), 1);
+        assert_eq!(statistics.min(), Some(-3.25));
+        assert_eq!(statistics.max(), Some(-3.25));
+        assert_eq!(statistics.mean(), Some(-3.25));
+    }
+
+    #[test]
+    fn nan_ignored() {
+        let mut statistics = Statistics::new();
+
+        statistics.add(f32::NAN);
+        assert_eq!(statistics.count(), 0);
+        assert_eq!(statistics.mean(), None);
+
+        statistics.add(10.0);
+        statistics.add(f32::NAN);
+        statistics.add(20.0);
+
+        assert_eq!(statistics.count(), 2);
+        assert_eq!(statistics.min(), Some(10.0));
+        assert_eq!(statistics.max(), Some(20.0));
+        assert_eq!(statistics.mean(), Some(15.0));
+    }
+
+    #[test]
+    fn reset() {
+        let mut statistics = Statistics::new();
+
+        statistics.add(5.0);
+        statistics.add(7.0);
+        statistics.reset();
+
+        assert_eq!(statistics.count(), 0);
+        assert_eq!(statistics.min(), None);
+        assert_eq!(statistics.max(), None);
+        assert_eq!(statistics.mean(), None);
+
+        statistics.add(1.0);
+
+        assert_eq!(statistics.min(), Some(1.0));
+        assert_eq!(statistics.max(), Some(1.0));
+        assert_eq!(statistics.mean(), Some(1.0));
+    }
+}
@@ -0,0 +1,232 @@
+//! Batches of samples sent in one packet
+//!
+//! A batch is sent as `[base index u32][count u8]` followed by the samples, oldest first. Each
+//! sample is sent as `[delta u16][len u8][payload]`. The delta of the first sample is its age at
+//! the transmission of the batch, the delta of every other sample the time since the previous
+//! sample (s). The samples are numbered consecutively starting with the base index.
+//!
+//! Samples with an unknown age (taken before the last start of the node or older than the delta
+//! can express) carry the delta `AGE_UNKNOWN`; the delta of the next sample with a known age is
+//! relative to the last sample with a known age, or its age if there is none.
+//!
+//! The module does not depend on the rest of the crate, so that its tests run on the host with
+//! `make test`.
+
+/// Length of the header of a batch
+pub const HEADER_LEN: usize = 5;
+/// Length of the header of a sample
+pub const SAMPLE_HEADER_LEN: usize = 3;
+/// Delta of a sample with an unknown age
+pub const AGE_UNKNOWN: u16 = u16::MAX;
+
+#[derive(Debug)]
+pub enum Error {
+    BufferTooShort,
+    /// a sample is older than the previous one or the payload is too long
+    Sample,
+}
+
+/// Writes samples into a buffer
+pub struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    /// age of the last sample with a known age
+    age: Option<u16>,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buffer: &'a mut [u8], base_index: u32) -> Result<Writer<'a>, Error> {
+        if buffer.len() < HEADER_LEN {
+            return Err(Error::BufferTooShort);
+        }
+
+        buffer[0..4].copy_from_slice(&base_index.to_le_bytes()[..]);
+        buffer[4] = 0;
+
+        Ok(Writer {
+            buffer,
+            len: HEADER_LEN,
+            age: None,
+        })
+    }
+
+    /// Append a sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `age` - time between the sample and the transmission of the batch (s); `None` or ages
+    ///   from `AGE_UNKNOWN` on are sent as unknown
+    /// * `payload` - payload of the sample
+    ///
+    pub fn push(&mut self, age: Option<u32>, payload: &[u8]) -> Result<(), Error> {
+        let len = SAMPLE_HEADER_LEN + payload.len();
+        let age = age
+            .filter(|age| *age < AGE_UNKNOWN as u32)
+            .map(|age| age as u16);
+        let delta = match (self.age, age) {
+            (_, None) => AGE_UNKNOWN,
+            (Some(previous), Some(age)) if previous < age => return Err(Error::Sample),
+            (Some(previous), Some(age)) => previous - age,
+            (None, Some(age)) => age,
+        };
+
+        if payload.len() > u8::MAX as usize || self.buffer[4] == u8::MAX {
+            return Err(Error::Sample);
+        }
+
+        if self.len + len > self.buffer.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        let data = &mut self.buffer[self.len..self.len + len];
+
+        data[0..2].copy_from_slice(&delta.to_le_bytes()[..]);
+        data[2] = payload.len() as u8;
+        data[SAMPLE_HEADER_LEN..].copy_from_slice(payload);
+        self.buffer[4] += 1;
+        self.len += len;
+        self.age = age.or(self.age);
+
+        Ok(())
+    }
+
+    /// Number of bytes written including the header
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// No samples have been written.
+    pub fn is_empty(&self) -> bool {
+        self.len == HEADER_LEN
+    }
+}
+
+pub struct Sample<'a> {
+    pub index: u32,
+    /// time between the sample and the transmission of the batch (s); `None` if unknown
+    pub age: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+/// Iterator over the samples of a batch
+///
+/// The iteration stops after the last sample or at the first malformed sample.
+pub struct Samples<'a> {
+    data: &'a [u8],
+    index: u32,
+    /// age of the last sample with a known age
+    age: Option<u16>,
+    remaining: u8,
+}
+
+impl<'a> Samples<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Option<Samples<'a>> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        Some(Samples {
+            data: &data[HEADER_LEN..],
+            index: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            age: None,
+            remaining: data[4],
+        })
+    }
+}
+
+impl<'a> Iterator for Samples<'a> {
+    type Item = Sample<'a>;
+
+    fn next(&mut self) -> Option<Sample<'a>> {
+        if self.remaining == 0 || self.data.len() < SAMPLE_HEADER_LEN {
+            return None;
+        }
+
+        let delta = u16::from_le_bytes([self.data[0], self.data[1]]);
+        let len = SAMPLE_HEADER_LEN + self.data[2] as usize;
+        let age = match (self.age, delta) {
+            (_, AGE_UNKNOWN) => Ok(None),
+            (Some(previous), delta) => previous.checked_sub(delta).map(Some).ok_or(()),
+            (None, delta) => Ok(Some(delta)),
+        };
+        let age = match (age, self.data.len() >= len) {
+            (Ok(age), true) => age,
+            _ => {
+                self.remaining = 0;
+
+                return None;
+            }
+        };
+        let sample = Sample {
+            index: self.index,
+            age,
+            payload: &self.data[SAMPLE_HEADER_LEN..len],
+        };
+
+        self.data = &self.data[len..];
+        self.index = self.index.wrapping_add(1);
+        self.age = age.or(self.age);
+        self.remaining -= 1;
+
+        Some(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ages() {
+        let mut buffer = [0u8; 32];
+        let mut writer = Writer::new(&mut buffer, 7).unwrap();
+
+        writer.push(Some(30), &[1]).unwrap();
+        writer.push(Some(20), &[2, 3]).unwrap();
+        writer.push(Some(0), &[]).unwrap();
+
+        let len = writer.len();
+        let samples: Vec<_> = Samples::from_bytes(&buffer[..len])
+            .unwrap()
+            .map(|sample| (sample.index, sample.age, sample.payload.to_vec()))
+            .collect();
+
+        assert_eq!(
+            samples,
+            vec![
+                (7, Some(30), vec![1]),
+                (8, Some(20), vec![2, 3]),
+                (9, Some(0), vec![])
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_ages() {
+        let mut buffer = [0u8; 32];
+        let mut writer = Writer::new(&mut buffer, 0).unwrap();
+
+        writer.push(None, &[]).unwrap();
+        writer.push(Some(100_000), &[]).unwrap();
+        writer.push(Some(40), &[]).unwrap();
+        writer.push(None, &[]).unwrap();
+        writer.push(Some(10), &[]).unwrap();
+
+        let len = writer.len();
+        let ages: Vec<_> = Samples::from_bytes(&buffer[..len])
+            .unwrap()
+            .map(|sample| sample.age)
+            .collect();
+
+        assert_eq!(ages, vec![None, None, Some(40), None, Some(10)]);
+    }
+
+    #[test]
+    fn older_sample() {
+        let mut buffer = [0u8; 32];
+        let mut writer = Writer::new(&mut buffer, 0).unwrap();
+
+        writer.push(Some(10), &[]).unwrap();
+        assert!(writer.push(Some(20), &[]).is_err());
+    }
+}
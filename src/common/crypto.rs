@@ -0,0 +1,96 @@
+//! Encryption of packet payloads with the CCM peripheral
+//!
+//! The CCM peripheral works on packets in the format `[S0][length][S1][payload]`. The functions
+//! below take care of the additional header bytes.
+
+use nrf52810_hal::ccm::{Ccm, CcmData};
+
+/// Length of the message integrity check appended to the cipher text
+pub const MIC_LEN: usize = 4;
+/// Longest plain text that can be encrypted
+pub const MAX_PLAIN_LEN: usize = 251;
+
+const HEADER_LEN: usize = 3;
+const PLAIN_PACKET_LEN: usize = HEADER_LEN + MAX_PLAIN_LEN;
+const CIPHER_PACKET_LEN: usize = PLAIN_PACKET_LEN + MIC_LEN;
+const SCRATCH_LEN: usize = CIPHER_PACKET_LEN + 16;
+
+/// Encrypt a plain text.
+///
+/// Returns the length of the cipher text (including the MIC) written to `cipher` or `None` if the
+/// plain text is too long or `cipher` is too short.
+pub fn encrypt(
+    ccm: &mut Ccm,
+    key: [u8; 16],
+    iv: [u8; 8],
+    plain: &[u8],
+    cipher: &mut [u8],
+) -> Option<usize> {
+    let len = plain.len() + MIC_LEN;
+
+    if plain.len() > MAX_PLAIN_LEN || cipher.len() < len {
+        return None;
+    }
+
+    let mut ccm_data = CcmData::new(key, iv);
+    let mut plain_packet = [0u8; PLAIN_PACKET_LEN];
+    let mut cipher_packet = [0u8; CIPHER_PACKET_LEN];
+    let mut scratch = [0u8; SCRATCH_LEN];
+
+    plain_packet[1] = plain.len() as u8;
+    plain_packet[HEADER_LEN..HEADER_LEN + plain.len()].copy_from_slice(plain);
+
+    ccm.encrypt_packet(
+        &mut ccm_data,
+        &plain_packet[..HEADER_LEN + plain.len()],
+        &mut cipher_packet[..HEADER_LEN + len],
+        &mut scratch,
+    )
+    .ok()?;
+
+    cipher[..len].copy_from_slice(&cipher_packet[HEADER_LEN..HEADER_LEN + len]);
+
+    Some(len)
+}
+
+/// Decrypt a cipher text.
+///
+/// Returns the length of the plain text written to `plain` or `None` if the cipher text could not
+/// be authenticated or `plain` is too short.
+pub fn decrypt(
+    ccm: &mut Ccm,
+    key: [u8; 16],
+    iv: [u8; 8],
+    cipher: &[u8],
+    plain: &mut [u8],
+) -> Option<usize> {
+    if cipher.len() < MIC_LEN || cipher.len() > MAX_PLAIN_LEN + MIC_LEN {
+        return None;
+    }
+
+    let len = cipher.len() - MIC_LEN;
+
+    if plain.len() < len {
+        return None;
+    }
+
+    let mut ccm_data = CcmData::new(key, iv);
+    let mut cipher_packet = [0u8; CIPHER_PACKET_LEN];
+    let mut plain_packet = [0u8; PLAIN_PACKET_LEN];
+    let mut scratch = [0u8; SCRATCH_LEN];
+
+    cipher_packet[1] = cipher.len() as u8;
+    cipher_packet[HEADER_LEN..HEADER_LEN + cipher.len()].copy_from_slice(cipher);
+
+    ccm.decrypt_packet(
+        &mut ccm_data,
+        &mut plain_packet,
+        &cipher_packet[..HEADER_LEN + cipher.len()],
+        &mut scratch,
+    )
+    .ok()?;
+
+    plain[..len].copy_from_slice(&plain_packet[HEADER_LEN..HEADER_LEN + len]);
+
+    Some(len)
+}
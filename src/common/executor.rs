@@ -0,0 +1,157 @@
+//! Minimal executor for futures
+//!
+//! `block_on` runs a single future (which may `join` several others) to completion. Between polls
+//! the CPU sleeps with `wfi`; the interrupt handlers of the drivers wake the future through the
+//! `WakerSlot` the future registered with.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use cortex_m::interrupt::{self, Mutex};
+
+/// Set by the waker; cleared before each poll
+static WOKEN: AtomicBool = AtomicBool::new(false);
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+
+/// Run a future to completion.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    // the future is not moved anymore; it lives on this stack frame until it completes
+    let mut future = unsafe { Pin::new_unchecked(&mut future) };
+    let waker = unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) };
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        WOKEN.store(false, Ordering::SeqCst);
+
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+
+        // interrupts are masked between the check and `wfi`, so that a wake-up in between is not
+        // missed (`wfi` also returns on masked interrupts); the handler runs after `free`
+        interrupt::free(|_| {
+            if !WOKEN.load(Ordering::SeqCst) {
+                cortex_m::asm::wfi();
+            }
+        });
+    }
+}
+
+/// Waker of a future waiting for an interrupt
+pub struct WakerSlot {
+    waker: Mutex<RefCell<Option<Waker>>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot {
+            waker: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    /// Register the waker of the future polled currently.
+    pub fn register(&self, waker: &Waker) {
+        interrupt::free(|cs| {
+            let mut slot = self.waker.borrow(cs).borrow_mut();
+
+            match &*slot {
+                Some(registered) if registered.will_wake(waker) => {}
+                _ => *slot = Some(waker.clone()),
+            }
+        });
+    }
+
+    /// Wake the registered future (called from interrupt handlers).
+    pub fn wake(&self) {
+        if let Some(waker) = interrupt::free(|cs| self.waker.borrow(cs).borrow_mut().take()) {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WakerSlot {
+    fn default() -> WakerSlot {
+        WakerSlot::new()
+    }
+}
+
+/// Future polling a closure
+pub struct PollFn<F> {
+    f: F,
+}
+
+impl<F> Unpin for PollFn<F> {}
+
+/// Create a future from a closure, e.g. checking an event of a peripheral.
+pub fn poll_fn<T, F: FnMut(&mut Context<'_>) -> Poll<T>>(f: F) -> PollFn<F> {
+    PollFn { f }
+}
+
+impl<T, F: FnMut(&mut Context<'_>) -> Poll<T>> Future for PollFn<F> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        (self.f)(cx)
+    }
+}
+
+/// Future running two futures concurrently
+pub struct Join<A: Future, B: Future> {
+    a: A,
+    b: B,
+    a_output: Option<A::Output>,
+    b_output: Option<B::Output>,
+}
+
+/// Run two futures concurrently and get both outputs.
+pub fn join<A: Future, B: Future>(a: A, b: B) -> Join<A, B> {
+    Join {
+        a,
+        b,
+        a_output: None,
+        b_output: None,
+    }
+}
+
+impl<A: Future, B: Future> Future for Join<A, B> {
+    type Output = (A::Output, B::Output);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the futures are never moved out of the pinned join
+        let this = unsafe { self.get_unchecked_mut() };
+
+        if this.a_output.is_none() {
+            if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.a) }.poll(cx) {
+                this.a_output = Some(output);
+            }
+        }
+
+        if this.b_output.is_none() {
+            if let Poll::Ready(output) = unsafe { Pin::new_unchecked(&mut this.b) }.poll(cx) {
+                this.b_output = Some(output);
+            }
+        }
+
+        match (this.a_output.take(), this.b_output.take()) {
+            (Some(a), Some(b)) => Poll::Ready((a, b)),
+            (a, b) => {
+                this.a_output = a;
+                this.b_output = b;
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+unsafe fn clone(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &VTABLE)
+}
+
+unsafe fn wake(_: *const ()) {
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+unsafe fn drop(_: *const ()) {}
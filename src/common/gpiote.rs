@@ -0,0 +1,217 @@
+//! Events on pin changes
+//!
+//! A channel detects edges of one pin with a precise event that can also be connected through PPI,
+//! but it keeps a high frequency clock running while enabled. Pins watched through the PORT event
+//! use the sense mechanism of the GPIO instead, which also works in System ON idle at a few nA; the
+//! interrupt handler tells the pins apart by their latches and watches each pin for the opposite
+//! level afterwards, so that every change is reported once.
+//!
+//! The callbacks are called from the interrupt handler; as any event raises the interrupt, it also
+//! wakes the CPU from `wfi`.
+
+use super::p0;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use nrf52810_pac as pac;
+use pac::interrupt;
+
+/// Number of channels
+pub const CHANNEL_COUNT: usize = 8;
+const PIN_COUNT: usize = 32;
+
+/// Channels with an event since the last `take_channel_events`
+static CHANNEL_EVENTS: AtomicU32 = AtomicU32::new(0);
+/// Pins with a change since the last `take_pin_events`
+static PIN_EVENTS: AtomicU32 = AtomicU32::new(0);
+static CHANNEL_CALLBACKS: Mutex<RefCell<[Option<fn(usize)>; CHANNEL_COUNT]>> =
+    Mutex::new(RefCell::new([None; CHANNEL_COUNT]));
+static PIN_CALLBACKS: Mutex<RefCell<[Option<fn(usize, bool)>; PIN_COUNT]>> =
+    Mutex::new(RefCell::new([None; PIN_COUNT]));
+
+/// Edge generating the event of a channel
+pub enum Polarity {
+    LoToHi,
+    HiToLo,
+    Toggle,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// the channel does not exist
+    Channel,
+}
+
+pub struct Gpiote<'a> {
+    gpiote: &'a mut pac::GPIOTE,
+}
+
+impl<'a> Gpiote<'a> {
+    pub fn new(gpiote: &'a mut pac::GPIOTE, nvic: &mut pac::NVIC) -> Gpiote<'a> {
+        #[allow(deprecated)]
+        nvic.enable(pac::interrupt::GPIOTE);
+
+        Gpiote { gpiote }
+    }
+
+    /// Generate events of a channel on edges of a pin.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - called from the interrupt handler with the channel
+    ///
+    pub fn enable_channel(
+        &mut self,
+        channel: usize,
+        pin: &p0::Pin<p0::Input>,
+        polarity: Polarity,
+        callback: Option<fn(usize)>,
+    ) -> Result<(), Error> {
+        if channel >= CHANNEL_COUNT {
+            return Err(Error::Channel);
+        }
+
+        interrupt::free(|cs| CHANNEL_CALLBACKS.borrow(cs).borrow_mut()[channel] = callback);
+        self.gpiote.config[channel].write(|w| {
+            let w = unsafe { w.mode().event().psel().bits(pin.pin() as u8) };
+
+            match polarity {
+                Polarity::LoToHi => w.polarity().lo_to_hi(),
+                Polarity::HiToLo => w.polarity().hi_to_lo(),
+                Polarity::Toggle => w.polarity().toggle(),
+            }
+        });
+        self.gpiote.events_in[channel].write(|w| w.events_in().not_generated());
+        self.gpiote
+            .intenset
+            .write(|w| unsafe { w.bits(1 << channel) });
+
+        Ok(())
+    }
+
+    pub fn disable_channel(&mut self, channel: usize) -> Result<(), Error> {
+        if channel >= CHANNEL_COUNT {
+            return Err(Error::Channel);
+        }
+
+        self.gpiote
+            .intenclr
+            .write(|w| unsafe { w.bits(1 << channel) });
+        self.gpiote.config[channel].write(|w| w.mode().disabled());
+        interrupt::free(|cs| CHANNEL_CALLBACKS.borrow(cs).borrow_mut()[channel] = None);
+
+        Ok(())
+    }
+
+    /// Address of the event of a channel for connecting it through PPI.
+    pub fn event_endpoint(&self, channel: usize) -> u32 {
+        &self.gpiote.events_in[channel] as *const _ as u32
+    }
+
+    /// Report changes of a pin through the PORT event.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - called from the interrupt handler with the pin and its new level (high)
+    ///
+    pub fn enable_pin(&mut self, pin: &mut p0::Pin<p0::Input>, callback: Option<fn(usize, bool)>) {
+        interrupt::free(|cs| PIN_CALLBACKS.borrow(cs).borrow_mut()[pin.pin()] = callback);
+
+        // the latches tell which pins changed; the detect mode applies to all pins
+        unsafe { &*pac::P0::ptr() }
+            .detectmode
+            .write(|w| w.detectmode().ldetect());
+
+        // watch for the opposite of the current level
+        match pin.is_high() {
+            true => pin.set_sense(p0::Sense::Low),
+            false => pin.set_sense(p0::Sense::High),
+        }
+
+        pin.clear_latch();
+        self.gpiote
+            .events_port
+            .write(|w| w.events_port().not_generated());
+        self.gpiote.intenset.write(|w| w.port().set());
+    }
+
+    pub fn disable_pin(&mut self, pin: &mut p0::Pin<p0::Input>) {
+        pin.set_sense(p0::Sense::Disabled);
+        pin.clear_latch();
+        interrupt::free(|cs| PIN_CALLBACKS.borrow(cs).borrow_mut()[pin.pin()] = None);
+    }
+
+    /// Sleep with `wfi` until a channel or pin has an event that has not been taken yet.
+    pub fn wait(&self) {
+        while interrupt::free(|_| {
+            let idle = CHANNEL_EVENTS.load(Ordering::SeqCst) == 0
+                && PIN_EVENTS.load(Ordering::SeqCst) == 0;
+
+            // interrupts are masked, so that an event in between is not missed; `wfi` also
+            // returns on masked interrupts
+            if idle {
+                cortex_m::asm::wfi();
+            }
+
+            idle
+        }) {}
+    }
+}
+
+/// Get the channels with events (bit n for channel n) since the last call.
+pub fn take_channel_events() -> u32 {
+    CHANNEL_EVENTS.swap(0, Ordering::SeqCst)
+}
+
+/// Get the pins that changed (bit n for pin n) since the last call.
+pub fn take_pin_events() -> u32 {
+    PIN_EVENTS.swap(0, Ordering::SeqCst)
+}
+
+#[interrupt]
+fn GPIOTE() {
+    let device = unsafe { pac::Peripherals::steal() };
+    let gpiote = &device.GPIOTE;
+    let p0 = &device.P0;
+
+    for channel in 0..CHANNEL_COUNT {
+        if gpiote.events_in[channel].read().events_in().is_generated() {
+            gpiote.events_in[channel].write(|w| w.events_in().not_generated());
+            CHANNEL_EVENTS.fetch_or(1 << channel, Ordering::SeqCst);
+
+            if let Some(callback) =
+                interrupt::free(|cs| CHANNEL_CALLBACKS.borrow(cs).borrow()[channel])
+            {
+                callback(channel);
+            }
+        }
+    }
+
+    if gpiote.events_port.read().events_port().is_generated() {
+        gpiote
+            .events_port
+            .write(|w| w.events_port().not_generated());
+
+        let latch = p0.latch.read().bits();
+        let levels = p0.in_.read().bits();
+
+        for pin in (0..PIN_COUNT).filter(|pin| latch & (1 << pin) != 0) {
+            let high = levels & (1 << pin) != 0;
+
+            // watch for the opposite level before clearing the latch, so that it is not set again
+            match high {
+                true => p0.pin_cnf[pin].modify(|_, w| w.sense().low()),
+                false => p0.pin_cnf[pin].modify(|_, w| w.sense().high()),
+            }
+        }
+
+        p0.latch.write(|w| unsafe { w.bits(latch) });
+        PIN_EVENTS.fetch_or(latch, Ordering::SeqCst);
+
+        for pin in (0..PIN_COUNT).filter(|pin| latch & (1 << pin) != 0) {
+            if let Some(callback) = interrupt::free(|cs| PIN_CALLBACKS.borrow(cs).borrow()[pin]) {
+                callback(pin, levels & (1 << pin) != 0);
+            }
+        }
+    }
+}
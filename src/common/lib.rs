@@ -1,17 +1,33 @@
 #![no_std]
 
+pub mod aggregate;
+pub mod batch;
 pub mod clock;
+pub mod crypto;
+pub mod executor;
+pub mod gpiote;
+pub mod link;
+pub mod log;
 pub mod lsm303agr;
 pub mod mmc5603nj;
+pub mod network;
+pub mod nvmc;
 pub mod p0;
+pub mod packet;
 pub mod power;
+pub mod ppi;
 pub mod radio;
+pub mod report;
 pub mod rng;
 pub mod rng2;
 pub mod rtc;
 pub mod saadc;
 pub mod sht3;
 pub mod sht4x;
+pub mod spim;
+pub mod sync;
 pub mod timer;
+pub mod tlv;
 pub mod twim;
+pub mod uarte;
 pub mod utils;
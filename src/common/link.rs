@@ -0,0 +1,46 @@
+//! Transmit power control based on the RSSI reported back by the gateway
+
+use super::radio::TxPower;
+
+pub struct PowerControl {
+    power: TxPower,
+    target_rssi: u8,
+    hysteresis: u8,
+}
+
+impl PowerControl {
+    /// Create a power control starting at the highest level.
+    ///
+    /// # Arguments
+    ///
+    /// * `target_rssi` - RSSI at the gateway to aim for (magnitude in -dBm)
+    /// * `hysteresis` - deviation from the target (in dB) that is tolerated before the power is changed
+    ///
+    pub fn new(target_rssi: u8, hysteresis: u8) -> PowerControl {
+        PowerControl {
+            power: TxPower::Pos4dBm,
+            target_rssi,
+            hysteresis,
+        }
+    }
+
+    pub fn power(&self) -> TxPower {
+        self.power
+    }
+
+    /// Adjust the power based on the RSSI reported in an acknowledgement.
+    pub fn on_ack(&mut self, rssi: u8) {
+        if rssi > self.target_rssi.saturating_add(self.hysteresis) {
+            // signal weaker than targeted
+            self.power = self.power.higher();
+        } else if rssi < self.target_rssi.saturating_sub(self.hysteresis) {
+            // signal stronger than targeted
+            self.power = self.power.lower();
+        }
+    }
+
+    /// Increase the power after a packet was not acknowledged.
+    pub fn on_missing_ack(&mut self) {
+        self.power = self.power.higher();
+    }
+}
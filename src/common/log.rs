@@ -0,0 +1,379 @@
+//! Ring log of measurements in flash
+//!
+//! The log keeps the samples of a node until the gateway acknowledged them. It uses the
+//! `LOG_PAGES` pages below the network configuration. Each page starts with a header
+//! `[magic][erase count][sequence]` followed by entries of `ENTRY_WORDS` words:
+//!
+//! `[marker, generation and length][index][local time (s)][acknowledged][records]`
+//!
+//! The acknowledged word is erased (all ones) until the sample has been acknowledged; it is then
+//! cleared without erasing the page. If all pages are full, the oldest page is erased, also if it
+//! holds samples that have not been acknowledged. The generation is incremented at every start of
+//! the node, as the local time starts anew, while the indices continue after the newest entry, so
+//! that the gateway does not mistake the samples of a new start for samples it already received.
+
+use crate::network;
+use crate::nvmc;
+
+/// Number of pages of the log
+pub const LOG_PAGES: u32 = 8;
+/// Start address of the log
+pub const LOG_ADDRESS: u32 = network::NETWORK_PAGE_ADDRESS - LOG_PAGES * nvmc::PAGE_SIZE;
+/// Longest payload of an entry
+pub const MAX_RECORDS_LEN: usize = 80;
+
+const PAGE_MAGIC: u32 = 0x4D4C_4F47;
+const PAGE_HEADER_WORDS: usize = 3;
+const ENTRY_MARKER: u32 = 0xA5;
+const ENTRY_HEADER_WORDS: usize = 4;
+const ENTRY_WORDS: usize = ENTRY_HEADER_WORDS + MAX_RECORDS_LEN / 4;
+const ENTRIES_PER_PAGE: u32 =
+    (nvmc::PAGE_SIZE - PAGE_HEADER_WORDS as u32 * 4) / (ENTRY_WORDS as u32 * 4);
+const ERASED: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug)]
+pub enum Error {
+    Nvmc(nvmc::Error),
+    TooLong,
+}
+
+impl From<nvmc::Error> for Error {
+    fn from(error: nvmc::Error) -> Self {
+        Error::Nvmc(error)
+    }
+}
+
+/// Sample read from the log
+#[derive(Clone, Copy)]
+pub struct Entry {
+    /// address of the entry, for acknowledging it
+    pub address: u32,
+    pub index: u32,
+    /// local time of the sample (s)
+    pub time: u32,
+    /// the sample was taken since the last start of the node
+    pub current: bool,
+    pub records: [u8; MAX_RECORDS_LEN],
+    pub len: usize,
+}
+
+impl Entry {
+    pub fn new() -> Entry {
+        Entry {
+            address: 0,
+            index: 0,
+            time: 0,
+            current: false,
+            records: [0; MAX_RECORDS_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Default for Entry {
+    fn default() -> Entry {
+        Entry::new()
+    }
+}
+
+/// State of the log reported in the diagnostics of the node
+pub struct Status {
+    /// samples that have not been acknowledged
+    pub pending: u32,
+    /// samples that can be written before samples that have not been acknowledged are overwritten
+    pub free: u32,
+    /// highest number of erases of a page of the log
+    pub erases: u32,
+}
+
+pub struct Log {
+    generation: u16,
+    /// page and entry written next
+    page: u32,
+    entry: u32,
+    sequence: u32,
+    /// index following the newest entry
+    next_index: u32,
+}
+
+impl Log {
+    /// Find the end of the log; pages that do not belong to the log are erased.
+    pub fn load(nvmc: &mut nvmc::Nvmc) -> Result<Log, Error> {
+        let mut newest: Option<(u32, u32)> = None;
+        let mut generation = 0u16;
+
+        for page in 0..LOG_PAGES {
+            let mut header = [0u32; PAGE_HEADER_WORDS];
+
+            nvmc.read(page_address(page), &mut header)?;
+
+            if header[0] != PAGE_MAGIC {
+                nvmc.erase_page(page_address(page))?;
+                nvmc.write(page_address(page), &[PAGE_MAGIC, 1, 0])?;
+            }
+
+            let sequence = sequence(nvmc, page)?;
+
+            if newest.map_or(true, |(_, newest)| sequence > newest) {
+                newest = Some((page, sequence));
+            }
+
+            for entry in 0..ENTRIES_PER_PAGE {
+                let word = read_word(nvmc, entry_address(page, entry))?;
+
+                if word >> 24 == ENTRY_MARKER {
+                    let entry_generation = (word >> 8) as u16;
+
+                    if entry_generation.wrapping_sub(generation) as i16 > 0 {
+                        generation = entry_generation;
+                    }
+                }
+            }
+        }
+
+        let (page, sequence) = newest.unwrap_or((0, 0));
+        let mut entry = 0;
+
+        while entry < ENTRIES_PER_PAGE && read_word(nvmc, entry_address(page, entry))? != ERASED {
+            entry += 1;
+        }
+
+        // the newest entry is the last one of the previous page if the current page is empty
+        let newest_entry = match entry {
+            0 => entry_address((page + LOG_PAGES - 1) % LOG_PAGES, ENTRIES_PER_PAGE - 1),
+            _ => entry_address(page, entry - 1),
+        };
+        let next_index = match read_word(nvmc, newest_entry)? >> 24 == ENTRY_MARKER {
+            true => read_word(nvmc, newest_entry + 4)?.wrapping_add(1),
+            false => 0,
+        };
+
+        Ok(Log {
+            generation: generation.wrapping_add(1),
+            page,
+            entry,
+            sequence,
+            next_index,
+        })
+    }
+
+    /// Index following the newest sample in the log (0 for an empty log).
+    ///
+    /// The node continues counting from here after a start.
+    pub fn next_index(&self) -> u32 {
+        self.next_index
+    }
+
+    /// Append a sample; returns the address of the entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - local time of the sample (s)
+    /// * `records` - payload of the sample
+    ///
+    pub fn append(
+        &mut self,
+        nvmc: &mut nvmc::Nvmc,
+        index: u32,
+        time: u32,
+        records: &[u8],
+    ) -> Result<u32, Error> {
+        if records.len() > MAX_RECORDS_LEN {
+            return Err(Error::TooLong);
+        }
+
+        if self.entry >= ENTRIES_PER_PAGE {
+            self.next_page(nvmc)?;
+        }
+
+        let mut words = [0u32; ENTRY_WORDS];
+
+        words[0] = ENTRY_MARKER << 24 | (self.generation as u32) << 8 | records.len() as u32;
+        words[1] = index;
+        words[2] = time;
+        words[3] = ERASED;
+
+        for (cntr, byte) in records.iter().enumerate() {
+            words[ENTRY_HEADER_WORDS + cntr / 4] |= (*byte as u32) << (cntr % 4 * 8);
+        }
+
+        let address = entry_address(self.page, self.entry);
+
+        nvmc.write(address, &words)?;
+        self.entry += 1;
+        self.next_index = index.wrapping_add(1);
+
+        Ok(address)
+    }
+
+    /// Mark a sample as acknowledged.
+    pub fn acknowledge(&mut self, nvmc: &mut nvmc::Nvmc, address: u32) -> Result<(), Error> {
+        nvmc.write(address + 12, &[0])?;
+
+        Ok(())
+    }
+
+    /// Read the oldest samples that have not been acknowledged.
+    ///
+    /// Only samples of the same start of the node with consecutive indices are read, so that
+    /// they can be sent as a batch. Returns the number of entries read.
+    pub fn read_pending(&self, nvmc: &nvmc::Nvmc, entries: &mut [Entry]) -> Result<usize, Error> {
+        let mut count = 0;
+
+        for page in self.pages_by_age(nvmc)?.iter() {
+            for entry in 0..self.entries_in(*page) {
+                let address = entry_address(*page, entry);
+                let mut header = [0u32; ENTRY_HEADER_WORDS];
+
+                nvmc.read(address, &mut header)?;
+
+                if header[0] >> 24 != ENTRY_MARKER || header[3] != ERASED {
+                    continue;
+                }
+
+                let current = (header[0] >> 8) as u16 == self.generation;
+
+                if count > 0 {
+                    let previous = &entries[count - 1];
+
+                    if previous.current != current || header[1] != previous.index.wrapping_add(1) {
+                        return Ok(count);
+                    }
+                }
+
+                if count == entries.len() {
+                    return Ok(count);
+                }
+
+                let mut words = [0u32; MAX_RECORDS_LEN / 4];
+                let len = (header[0] as u8 as usize).min(MAX_RECORDS_LEN);
+                let target = &mut entries[count];
+
+                nvmc.read(address + ENTRY_HEADER_WORDS as u32 * 4, &mut words)?;
+
+                for (cntr, word) in words.iter().enumerate() {
+                    target.records[cntr * 4..cntr * 4 + 4].copy_from_slice(&word.to_le_bytes());
+                }
+
+                target.address = address;
+                target.index = header[1];
+                target.time = header[2];
+                target.current = current;
+                target.len = len;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Get the state of the log.
+    pub fn status(&self, nvmc: &nvmc::Nvmc) -> Result<Status, Error> {
+        let mut status = Status {
+            pending: 0,
+            free: ENTRIES_PER_PAGE - self.entry.min(ENTRIES_PER_PAGE),
+            erases: 0,
+        };
+        // pages up to the oldest page with pending samples can be reused
+        let mut reusable = true;
+
+        for page in self.pages_by_age(nvmc)?.iter() {
+            let mut pending = 0;
+
+            for entry in 0..self.entries_in(*page) {
+                let mut header = [0u32; ENTRY_HEADER_WORDS];
+
+                nvmc.read(entry_address(*page, entry), &mut header)?;
+
+                if header[0] >> 24 == ENTRY_MARKER && header[3] == ERASED {
+                    pending += 1;
+                }
+            }
+
+            reusable = reusable && pending == 0;
+
+            if reusable && *page != self.page {
+                status.free += ENTRIES_PER_PAGE;
+            }
+
+            status.pending += pending;
+            status.erases = status.erases.max(read_word(nvmc, page_address(*page) + 4)?);
+        }
+
+        Ok(status)
+    }
+
+    /// Erase the oldest page and continue writing there.
+    fn next_page(&mut self, nvmc: &mut nvmc::Nvmc) -> Result<(), Error> {
+        let page = (self.page + 1) % LOG_PAGES;
+        let erases = read_word(nvmc, page_address(page) + 4)?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        nvmc.erase_page(page_address(page))?;
+        nvmc.write(
+            page_address(page),
+            &[PAGE_MAGIC, erases.saturating_add(1), self.sequence],
+        )?;
+        self.page = page;
+        self.entry = 0;
+
+        Ok(())
+    }
+
+    /// Get the pages from the oldest to the page written currently.
+    fn pages_by_age(&self, nvmc: &nvmc::Nvmc) -> Result<[u32; LOG_PAGES as usize], Error> {
+        let mut pages = [0u32; LOG_PAGES as usize];
+        let mut sequences = [0u32; LOG_PAGES as usize];
+
+        for page in 0..LOG_PAGES {
+            pages[page as usize] = page;
+            sequences[page as usize] = sequence(nvmc, page)?;
+        }
+
+        // few pages; sort by the distance of the sequence to the current page
+        for cntr in 1..pages.len() {
+            let mut position = cntr;
+
+            while position > 0
+                && self.age(sequences[pages[position - 1] as usize])
+                    < self.age(sequences[pages[position] as usize])
+            {
+                pages.swap(position - 1, position);
+                position -= 1;
+            }
+        }
+
+        Ok(pages)
+    }
+
+    fn age(&self, sequence: u32) -> u32 {
+        self.sequence.wrapping_sub(sequence)
+    }
+
+    fn entries_in(&self, page: u32) -> u32 {
+        match page == self.page {
+            true => self.entry,
+            false => ENTRIES_PER_PAGE,
+        }
+    }
+}
+
+fn page_address(page: u32) -> u32 {
+    LOG_ADDRESS + page * nvmc::PAGE_SIZE
+}
+
+fn entry_address(page: u32, entry: u32) -> u32 {
+    page_address(page) + PAGE_HEADER_WORDS as u32 * 4 + entry * ENTRY_WORDS as u32 * 4
+}
+
+fn sequence(nvmc: &nvmc::Nvmc, page: u32) -> Result<u32, Error> {
+    read_word(nvmc, page_address(page) + 8)
+}
+
+fn read_word(nvmc: &nvmc::Nvmc, address: u32) -> Result<u32, Error> {
+    let mut word = [0u32; 1];
+
+    nvmc.read(address, &mut word)?;
+
+    Ok(word[0])
+}
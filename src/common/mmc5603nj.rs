@@ -1,7 +1,8 @@
-use super::twim;
+use embedded_hal::blocking::i2c;
 
 #[derive(core::fmt::Debug)]
-pub enum Mmc5603njError {
+pub enum Mmc5603njError<E> {
+    EmbeddedError(E),
     CrcError,
     TimerError,
     NotDoneError,
@@ -13,16 +14,19 @@ pub enum Mmc5603njBias {
     None,
 }
 
-pub struct MMC5603NJ<'a> {
-    twim: &'a mut twim::Twim<'a>,
+pub struct MMC5603NJ<'a, I2C> {
+    i2c: &'a mut I2C,
     address: u8,
     buffer: [u8; 1],
 }
 
-impl<'a> MMC5603NJ<'a> {
-    pub fn new(twim: &'a mut twim::Twim<'a>, address: u8) -> MMC5603NJ<'a> {
+impl<'a, I2C, E> MMC5603NJ<'a, I2C>
+where
+    I2C: i2c::Write<Error = E> + i2c::WriteRead<Error = E>,
+{
+    pub fn new(i2c: &'a mut I2C, address: u8) -> MMC5603NJ<'a, I2C> {
         MMC5603NJ {
-            twim: twim,
+            i2c,
             address: address,
             buffer: [0; 1],
         }
@@ -31,51 +35,49 @@ impl<'a> MMC5603NJ<'a> {
     pub fn start_magnetic__measruement(
         &mut self,
         bias: Mmc5603njBias,
-    ) -> Result<(), Mmc5603njError> {
+    ) -> Result<(), Mmc5603njError<E>> {
         match bias {
             Mmc5603njBias::Set => {
                 let buffer = [0x1B, 0x08];
-                self.twim.start_write(self.address, &buffer);
-                self.twim.wait().unwrap();
+                self.i2c
+                    .write(self.address, &buffer)
+                    .map_err(Mmc5603njError::EmbeddedError)?;
             }
             Mmc5603njBias::Reset => {
                 let buffer = [0x1B, 0x10];
-                self.twim.start_write(self.address, &buffer);
-                self.twim.wait().unwrap();
+                self.i2c
+                    .write(self.address, &buffer)
+                    .map_err(Mmc5603njError::EmbeddedError)?;
             }
             _ => {}
         }
 
         let buffer = [0x1B, 0x01];
-        self.twim.start_write(self.address, &buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write(self.address, &buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         Ok(())
     }
 
-    pub fn wait_for_magnetic_measurement(&mut self) -> Result<(f32, f32, f32), Mmc5603njError> {
+    pub fn wait_for_magnetic_measurement(&mut self) -> Result<(f32, f32, f32), Mmc5603njError<E>> {
         self.buffer[0] = 0x18;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
         let mut stat_buffer: [u8; 1] = [0; 1];
-        self.twim.start_read(self.address, &mut stat_buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write_read(self.address, &self.buffer, &mut stat_buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         while stat_buffer[0] & 0x40 != 0x40 {
-            self.twim.start_write(self.address, &self.buffer);
-            self.twim.wait().unwrap();
-            self.twim.start_read(self.address, &mut stat_buffer);
-            self.twim.wait().unwrap();
+            self.i2c
+                .write_read(self.address, &self.buffer, &mut stat_buffer)
+                .map_err(Mmc5603njError::EmbeddedError)?;
         }
 
         self.buffer[0] = 0x00;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
         let mut buffer: [u8; 6] = [0; 6];
-        self.twim.start_read(self.address, &mut buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write_read(self.address, &self.buffer, &mut buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         let x = u16::from_be_bytes([buffer[0], buffer[1]]) as f32 / 1024f32;
         let y = u16::from_be_bytes([buffer[2], buffer[3]]) as f32 / 1024f32;
@@ -84,37 +86,33 @@ impl<'a> MMC5603NJ<'a> {
         Ok((x, y, z))
     }
 
-    pub fn start_temperature_measurement(&mut self) -> Result<(), Mmc5603njError> {
+    pub fn start_temperature_measurement(&mut self) -> Result<(), Mmc5603njError<E>> {
         let buffer = [0x1B, 0x02];
-        self.twim.start_write(self.address, &buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write(self.address, &buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         Ok(())
     }
 
-    pub fn wait_for_temperature_measurement(&mut self) -> Result<f32, Mmc5603njError> {
+    pub fn wait_for_temperature_measurement(&mut self) -> Result<f32, Mmc5603njError<E>> {
         self.buffer[0] = 0x18;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
         let mut stat_buffer: [u8; 1] = [0; 1];
-        self.twim.start_read(self.address, &mut stat_buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write_read(self.address, &self.buffer, &mut stat_buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         while stat_buffer[0] & 0x80 != 0x80 {
-            self.twim.start_write(self.address, &self.buffer);
-            self.twim.wait().unwrap();
-            self.twim.start_read(self.address, &mut stat_buffer);
-            self.twim.wait().unwrap();
+            self.i2c
+                .write_read(self.address, &self.buffer, &mut stat_buffer)
+                .map_err(Mmc5603njError::EmbeddedError)?;
         }
 
         self.buffer[0] = 0x09;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
         let mut buffer: [u8; 1] = [0; 1];
-        self.twim.start_read(self.address, &mut buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .write_read(self.address, &self.buffer, &mut buffer)
+            .map_err(Mmc5603njError::EmbeddedError)?;
 
         let temperature = -75f32 + (buffer[0] as f32 * 0.8f32);
 
@@ -0,0 +1,342 @@
+//! Network membership stored in flash
+//!
+//! Nodes store the parameters assigned by the gateway when joining. The gateway stores the table of
+//! enrolled nodes. Both use the page at `NETWORK_PAGE_ADDRESS`.
+//!
+//! Each node transmits in its own slot of the reporting interval. The reporting intervals start at
+//! multiples of the interval in network time. The slots follow each other after the time beacon at
+//! the start of the interval.
+
+use super::nvmc;
+
+/// Flash page holding the network configuration (last page of the flash)
+pub const NETWORK_PAGE_ADDRESS: u32 = 0x2F000;
+/// Number of nodes the gateway can enroll
+pub const MAX_NODES: usize = 64;
+/// Start of the first slot after the start of the reporting interval (ms)
+pub const SLOT_START_MS: u32 = 100;
+/// Length of a slot (ms)
+pub const SLOT_LENGTH_MS: u32 = 100;
+
+const CONFIG_MAGIC: u32 = 0x4E43_4631;
+const NODE_MAGIC: u32 = 0x4E4F_4445;
+const CONFIG_WORDS: usize = 7;
+const NODE_WORDS: usize = 8;
+const NODE_RECORDS: u32 = nvmc::PAGE_SIZE / (NODE_WORDS as u32 * 4);
+
+/// Parameters assigned to a node by the gateway
+#[derive(Clone, Copy)]
+pub struct NetworkConfig {
+    pub short_address: u16,
+    pub session_key: [u8; 16],
+    /// Reporting interval in s
+    pub interval: u16,
+    /// Start of the transmit slot within the reporting interval (ms)
+    pub slot_offset: u32,
+}
+
+impl NetworkConfig {
+    /// Load the configuration; returns `None` if the node has not joined a network.
+    pub fn load(nvmc: &nvmc::Nvmc) -> Option<NetworkConfig> {
+        let mut words = [0u32; CONFIG_WORDS];
+
+        nvmc.read(NETWORK_PAGE_ADDRESS, &mut words).ok()?;
+
+        if words[0] != CONFIG_MAGIC {
+            return None;
+        }
+
+        Some(NetworkConfig {
+            short_address: words[1] as u16,
+            session_key: key_from_words(&words[2..6]),
+            interval: (words[1] >> 16) as u16,
+            slot_offset: words[6],
+        })
+    }
+
+    pub fn store(&self, nvmc: &mut nvmc::Nvmc) -> Result<(), nvmc::Error> {
+        let mut words = [0u32; CONFIG_WORDS];
+
+        words[0] = CONFIG_MAGIC;
+        words[1] = self.short_address as u32 | (self.interval as u32) << 16;
+        key_to_words(&self.session_key, &mut words[2..6]);
+        words[6] = self.slot_offset;
+
+        nvmc.erase_page(NETWORK_PAGE_ADDRESS)?;
+        nvmc.write(NETWORK_PAGE_ADDRESS, &words)
+    }
+}
+
+/// Node enrolled by the gateway
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub device_id: u64,
+    pub short_address: u16,
+    pub session_key: [u8; 16],
+    /// Transmit slot
+    pub slot: u16,
+    /// Nonce of the join request the node was enrolled with (not stored in flash)
+    pub nonce: Option<u32>,
+}
+
+impl Node {
+    /// Start of the node's slot within the reporting interval (ms)
+    pub fn slot_offset(&self) -> u32 {
+        SLOT_START_MS + self.slot as u32 * SLOT_LENGTH_MS
+    }
+}
+
+/// Nodes enrolled by the gateway
+///
+/// The nodes are stored as records appended to the flash page. A later record for a device
+/// replaces an earlier one. The page is rewritten once it is full. Each node is assigned a slot of
+/// its own, which it keeps when it joins again.
+///
+/// Enrolling a node only changes the table in RAM, as erasing the page takes longer than the node
+/// waits for the join accept; `store` writes the changes to the flash afterwards.
+pub struct NodeTable {
+    nodes: [Option<Node>; MAX_NODES],
+    records: u32,
+    /// nodes changed since the last `store` (bit n for entry n)
+    unsaved: u64,
+}
+
+impl NodeTable {
+    pub fn load(nvmc: &nvmc::Nvmc) -> NodeTable {
+        let mut table = NodeTable {
+            nodes: [None; MAX_NODES],
+            records: 0,
+            unsaved: 0,
+        };
+
+        while table.records < NODE_RECORDS {
+            let mut words = [0u32; NODE_WORDS];
+
+            if nvmc
+                .read(record_address(table.records), &mut words)
+                .is_err()
+                || words[0] != NODE_MAGIC
+            {
+                break;
+            }
+
+            // the slot is stored incremented by one; 0 marks records written before slots were assigned
+            let slot = match (words[3] >> 16) as u16 {
+                0 => None,
+                slot => Some(slot - 1),
+            };
+            let mut node = Node {
+                device_id: words[1] as u64 | (words[2] as u64) << 32,
+                short_address: words[3] as u16,
+                session_key: key_from_words(&words[4..8]),
+                slot: slot.unwrap_or(0),
+                nonce: None,
+            };
+
+            if slot.is_none() {
+                node.slot = table.free_slot(node.device_id).unwrap_or(0);
+            }
+
+            table.insert(node);
+            table.records += 1;
+        }
+
+        table
+    }
+
+    /// Enroll a node with a new session key.
+    ///
+    /// A node enrolled before keeps its short address. A repeated join request (e.g. received
+    /// directly and through a relay) has the nonce the node was enrolled with; the node keeps its
+    /// session key then, as it only takes the first join accept. Returns `None` if the table is
+    /// full.
+    pub fn enroll(&mut self, device_id: u64, nonce: u32, session_key: [u8; 16]) -> Option<Node> {
+        if let Some(node) = self.find_by_device_id(device_id) {
+            if node.nonce == Some(nonce) {
+                return Some(*node);
+            }
+        }
+
+        let slot = self.free_slot(device_id)?;
+        let short_address = match self.find_by_device_id(device_id) {
+            Some(node) => node.short_address,
+            None => self
+                .nodes
+                .iter()
+                .flatten()
+                .map(|node| node.short_address)
+                .max()
+                .unwrap_or(0)
+                .checked_add(1)?,
+        };
+        let node = Node {
+            device_id,
+            short_address,
+            session_key,
+            slot,
+            nonce: Some(nonce),
+        };
+
+        let position = self.insert(node)?;
+
+        self.unsaved |= 1 << position;
+
+        Some(node)
+    }
+
+    /// Write the nodes enrolled since the last call to the flash.
+    ///
+    /// Nodes that could not be written are written with the next call.
+    pub fn store(&mut self, nvmc: &mut nvmc::Nvmc) -> Result<(), nvmc::Error> {
+        if self.records + self.unsaved.count_ones() > NODE_RECORDS {
+            // rewrite the page with the current nodes only
+            nvmc.erase_page(NETWORK_PAGE_ADDRESS)?;
+            self.records = 0;
+            self.unsaved = self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| node.is_some())
+                .fold(0, |unsaved, (position, _)| unsaved | 1 << position);
+        }
+
+        let unsaved = self.unsaved;
+
+        for position in (0..MAX_NODES).filter(|position| unsaved & (1 << position) != 0) {
+            if let Some(node) = self.nodes[position] {
+                self.write_record(nvmc, &node)?;
+            }
+
+            self.unsaved &= !(1 << position);
+        }
+
+        Ok(())
+    }
+
+    pub fn find_by_device_id(&self, device_id: u64) -> Option<&Node> {
+        self.nodes
+            .iter()
+            .flatten()
+            .find(|node| node.device_id == device_id)
+    }
+
+    pub fn find_by_short_address(&self, short_address: u16) -> Option<&Node> {
+        self.nodes
+            .iter()
+            .flatten()
+            .find(|node| node.short_address == short_address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Node> {
+        self.nodes.iter().flatten()
+    }
+
+    /// Get the slot of a node or the first slot not taken by another node.
+    fn free_slot(&self, device_id: u64) -> Option<u16> {
+        if let Some(node) = self.find_by_device_id(device_id) {
+            return Some(node.slot);
+        }
+
+        (0..MAX_NODES as u16).find(|slot| self.iter().all(|node| node.slot != *slot))
+    }
+
+    /// Insert or replace a node in RAM; returns the position or `None` if the table is full.
+    fn insert(&mut self, node: Node) -> Option<usize> {
+        let position = self
+            .nodes
+            .iter()
+            .position(|entry| match entry {
+                Some(entry) => entry.device_id == node.device_id,
+                None => false,
+            })
+            .or_else(|| self.nodes.iter().position(|entry| entry.is_none()))?;
+
+        self.nodes[position] = Some(node);
+
+        Some(position)
+    }
+
+    fn write_record(&mut self, nvmc: &mut nvmc::Nvmc, node: &Node) -> Result<(), nvmc::Error> {
+        let mut words = [0u32; NODE_WORDS];
+
+        words[0] = NODE_MAGIC;
+        words[1] = node.device_id as u32;
+        words[2] = (node.device_id >> 32) as u32;
+        words[3] = node.short_address as u32 | (node.slot as u32 + 1) << 16;
+        key_to_words(&node.session_key, &mut words[4..8]);
+
+        nvmc.write(record_address(self.records), &words)?;
+        self.records += 1;
+
+        Ok(())
+    }
+}
+
+/// Get the next start of a slot after a point in time.
+///
+/// # Arguments
+///
+/// * `network` - network time (µs)
+/// * `slot_offset` - start of the slot within the reporting interval (ms)
+/// * `interval` - reporting interval (s)
+///
+pub fn next_slot(network: u64, slot_offset: u32, interval: u16) -> u64 {
+    let interval = interval as u64 * 1_000_000;
+    let slot_offset = slot_offset as u64 * 1_000;
+
+    if interval == 0 {
+        return network;
+    }
+
+    (network.saturating_sub(slot_offset) / interval + 1) * interval + slot_offset
+}
+
+/// Get the deviation of a point in time from the nearest start of a slot (µs).
+///
+/// # Arguments
+///
+/// * `network` - network time (µs)
+/// * `slot_offset` - start of the slot within the reporting interval (ms)
+/// * `interval` - reporting interval (s)
+///
+pub fn slot_timing(network: u64, slot_offset: u32, interval: u16) -> i32 {
+    let interval = interval as i64 * 1_000_000;
+
+    if interval == 0 {
+        return 0;
+    }
+
+    let deviation = (network as i64 - slot_offset as i64 * 1_000).rem_euclid(interval);
+
+    let deviation = match deviation > interval / 2 {
+        true => deviation - interval,
+        false => deviation,
+    };
+
+    deviation.max(i32::MIN as i64).min(i32::MAX as i64) as i32
+}
+
+fn record_address(record: u32) -> u32 {
+    NETWORK_PAGE_ADDRESS + record * NODE_WORDS as u32 * 4
+}
+
+fn key_from_words(words: &[u32]) -> [u8; 16] {
+    let mut key = [0u8; 16];
+
+    for (cntr, word) in words.iter().enumerate() {
+        key[cntr * 4..cntr * 4 + 4].copy_from_slice(&word.to_le_bytes()[..]);
+    }
+
+    key
+}
+
+fn key_to_words(key: &[u8; 16], words: &mut [u32]) {
+    for (cntr, word) in words.iter_mut().enumerate() {
+        *word = u32::from_le_bytes([
+            key[cntr * 4],
+            key[cntr * 4 + 1],
+            key[cntr * 4 + 2],
+            key[cntr * 4 + 3],
+        ]);
+    }
+}
@@ -0,0 +1,92 @@
+use nrf52810_pac as pac;
+
+/// Size of a flash page in bytes
+pub const PAGE_SIZE: u32 = 4096;
+
+pub struct Nvmc {
+    nvmc: pac::NVMC,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Alignment,
+}
+
+impl Nvmc {
+    pub fn new(nvmc: pac::NVMC) -> Nvmc {
+        Nvmc { nvmc: nvmc }
+    }
+
+    /// Erase a page (all bits are set to 1).
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - start address of the page; must be aligned to `PAGE_SIZE`
+    ///
+    pub fn erase_page(&mut self, address: u32) -> Result<(), Error> {
+        if address % PAGE_SIZE != 0 {
+            return Err(Error::Alignment);
+        }
+
+        self.nvmc.config.write(|w| w.wen().een());
+        self.nvmc
+            .erasepage()
+            .write(|w| unsafe { w.erasepage().bits(address) });
+        self.wait_ready();
+        self.nvmc.config.write(|w| w.wen().ren());
+
+        Ok(())
+    }
+
+    /// Write words to flash.
+    ///
+    /// Writing can only clear bits; the words must have been erased before.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - start address; must be aligned to 4 bytes
+    /// * `data` - words to be written
+    ///
+    pub fn write(&mut self, address: u32, data: &[u32]) -> Result<(), Error> {
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+
+        self.nvmc.config.write(|w| w.wen().wen());
+
+        for (cntr, word) in data.iter().enumerate() {
+            unsafe {
+                core::ptr::write_volatile((address as usize + cntr * 4) as *mut u32, *word);
+            }
+            self.wait_ready();
+        }
+
+        self.nvmc.config.write(|w| w.wen().ren());
+
+        Ok(())
+    }
+
+    /// Read words from flash.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - start address; must be aligned to 4 bytes
+    /// * `data` - buffer for the words read
+    ///
+    pub fn read(&self, address: u32, data: &mut [u32]) -> Result<(), Error> {
+        if address % 4 != 0 {
+            return Err(Error::Alignment);
+        }
+
+        for (cntr, word) in data.iter_mut().enumerate() {
+            *word =
+                unsafe { core::ptr::read_volatile((address as usize + cntr * 4) as *const u32) };
+        }
+
+        Ok(())
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+}
@@ -1,3 +1,7 @@
+use core::convert::Infallible;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embedded_hal::digital::v2 as digital;
 use nrf52810_pac as pac;
 
 pub struct P0 {
@@ -89,3 +93,283 @@ impl P0 {
         });
     }
 }
+
+/// Pins handed out by `Pin::take`
+static TAKEN: AtomicU32 = AtomicU32::new(0);
+
+/// Pin not connected to anything (reset state)
+pub struct Disconnected;
+/// Pin reading its level
+pub struct Input;
+/// Pin driving its level
+pub struct Output;
+/// Pin pulling low or releasing the line (e.g. for I2C); the level can be read
+pub struct OpenDrain;
+
+/// Pin of port 0
+///
+/// Each pin can be taken once; the registers are accessed for this pin only, so that the pins can
+/// be used independently of each other and of the peripheral `P0`.
+pub struct Pin<MODE> {
+    pin: usize,
+    _mode: PhantomData<MODE>,
+}
+
+impl Pin<Disconnected> {
+    /// Take a pin.
+    ///
+    /// Returns `None` if the pin does not exist or has been taken before.
+    pub fn take(pin: usize) -> Option<Pin<Disconnected>> {
+        if pin >= 32 || TAKEN.fetch_or(1 << pin, Ordering::SeqCst) & (1 << pin) != 0 {
+            return None;
+        }
+
+        Some(Pin::new(pin))
+    }
+}
+
+impl<MODE> Pin<MODE> {
+    fn new(pin: usize) -> Pin<MODE> {
+        Pin {
+            pin,
+            _mode: PhantomData,
+        }
+    }
+
+    fn p0(&self) -> &pac::p0::RegisterBlock {
+        unsafe { &*pac::P0::ptr() }
+    }
+
+    fn mask(&self) -> u32 {
+        1 << self.pin
+    }
+
+    /// Number of the pin (e.g. for the PSEL registers of peripherals)
+    pub fn pin(&self) -> usize {
+        self.pin
+    }
+
+    pub fn into_input(self, pull: Pull) -> Pin<Input> {
+        self.p0().pin_cnf[self.pin].write(|w| {
+            let w = w.dir().input().input().connect();
+
+            match pull {
+                Pull::Disabled => w.pull().disabled(),
+                Pull::PullDown => w.pull().pulldown(),
+                Pull::PullUp => w.pull().pullup(),
+            }
+        });
+
+        Pin::new(self.pin)
+    }
+
+    /// Configure the pin as output.
+    ///
+    /// # Arguments
+    ///
+    /// * `high` - initial level; it is set before the pin starts driving
+    ///
+    pub fn into_output(self, drive: Drive, high: bool) -> Pin<Output> {
+        match high {
+            true => self.p0().outset.write(|w| unsafe { w.bits(self.mask()) }),
+            false => self.p0().outclr.write(|w| unsafe { w.bits(self.mask()) }),
+        }
+
+        self.p0().pin_cnf[self.pin].write(|w| {
+            let w = w.dir().output().input().disconnect();
+
+            match drive {
+                Drive::D0H1 => w.drive().d0h1(),
+                Drive::D0S1 => w.drive().d0s1(),
+                Drive::H0D1 => w.drive().h0d1(),
+                Drive::H0H1 => w.drive().h0h1(),
+                Drive::H0S1 => w.drive().h0s1(),
+                Drive::S0D1 => w.drive().s0d1(),
+                Drive::S0H1 => w.drive().s0h1(),
+                Drive::S0S1 => w.drive().s0s1(),
+            }
+        });
+
+        Pin::new(self.pin)
+    }
+
+    /// Configure the pin as open drain with the line released.
+    ///
+    /// The pin is an input while released and only drives while low, which is also the
+    /// configuration peripherals such as TWIM expect.
+    pub fn into_open_drain(self, pull: Pull) -> Pin<OpenDrain> {
+        self.p0().outclr.write(|w| unsafe { w.bits(self.mask()) });
+        self.p0().pin_cnf[self.pin].write(|w| {
+            let w = w.dir().input().input().connect().drive().s0d1();
+
+            match pull {
+                Pull::Disabled => w.pull().disabled(),
+                Pull::PullDown => w.pull().pulldown(),
+                Pull::PullUp => w.pull().pullup(),
+            }
+        });
+
+        Pin::new(self.pin)
+    }
+
+    /// Disconnect the pin (reset state, lowest power consumption).
+    pub fn into_disconnected(self) -> Pin<Disconnected> {
+        self.p0().pin_cnf[self.pin].reset();
+
+        Pin::new(self.pin)
+    }
+
+    /// Disconnect the pin and give it back, so that it can be taken again.
+    pub fn release(self) {
+        let pin = self.into_disconnected().pin;
+
+        TAKEN.fetch_and(!(1 << pin), Ordering::SeqCst);
+    }
+}
+
+impl Pin<Input> {
+    pub fn is_high(&self) -> bool {
+        self.p0().in_.read().bits() & self.mask() != 0
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+
+    /// Set the level the sense mechanism watches for (e.g. for the PORT event of GPIOTE).
+    pub fn set_sense(&mut self, sense: Sense) {
+        self.p0().pin_cnf[self.pin].modify(|_, w| match sense {
+            Sense::Disabled => w.sense().disabled(),
+            Sense::High => w.sense().high(),
+            Sense::Low => w.sense().low(),
+        });
+    }
+
+    /// Clear the latch of the pin, which is set when the sensed level is detected.
+    pub fn clear_latch(&mut self) {
+        self.p0().latch.write(|w| unsafe { w.bits(self.mask()) });
+    }
+}
+
+impl Pin<Output> {
+    /// Connect the input buffer, which some peripherals read the output back through (e.g. the
+    /// clock of SPIM).
+    pub fn connect_input(&mut self) {
+        self.p0().pin_cnf[self.pin].modify(|_, w| w.input().connect());
+    }
+
+    pub fn set_high(&mut self) {
+        self.p0().outset.write(|w| unsafe { w.bits(self.mask()) });
+    }
+
+    pub fn set_low(&mut self) {
+        self.p0().outclr.write(|w| unsafe { w.bits(self.mask()) });
+    }
+
+    pub fn toggle(&mut self) {
+        match self.is_high() {
+            true => self.set_low(),
+            false => self.set_high(),
+        }
+    }
+
+    /// The level the pin is set to
+    pub fn is_high(&self) -> bool {
+        self.p0().out.read().bits() & self.mask() != 0
+    }
+}
+
+impl Pin<OpenDrain> {
+    /// Release the line; the pull-up (or another device) determines its level.
+    pub fn set_high(&mut self) {
+        self.p0().dirclr.write(|w| unsafe { w.bits(self.mask()) });
+    }
+
+    /// Pull the line low.
+    pub fn set_low(&mut self) {
+        self.p0().dirset.write(|w| unsafe { w.bits(self.mask()) });
+    }
+
+    pub fn toggle(&mut self) {
+        match self.p0().dir.read().bits() & self.mask() != 0 {
+            true => self.set_high(),
+            false => self.set_low(),
+        }
+    }
+
+    /// The level of the line
+    pub fn is_high(&self) -> bool {
+        self.p0().in_.read().bits() & self.mask() != 0
+    }
+}
+
+impl digital::InputPin for Pin<Input> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(Self::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(Self::is_low(self))
+    }
+}
+
+impl digital::OutputPin for Pin<Output> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Self::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Self::set_low(self);
+        Ok(())
+    }
+}
+
+impl digital::StatefulOutputPin for Pin<Output> {
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        Ok(Self::is_high(self))
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        Ok(!Self::is_high(self))
+    }
+}
+
+impl digital::ToggleableOutputPin for Pin<Output> {
+    type Error = Infallible;
+
+    fn toggle(&mut self) -> Result<(), Infallible> {
+        Self::toggle(self);
+        Ok(())
+    }
+}
+
+impl digital::InputPin for Pin<OpenDrain> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(Self::is_high(self))
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(!Self::is_high(self))
+    }
+}
+
+impl digital::OutputPin for Pin<OpenDrain> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Self::set_high(self);
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Self::set_low(self);
+        Ok(())
+    }
+}
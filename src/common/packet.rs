@@ -0,0 +1,449 @@
+//! Packet types and flags shared by the gateway and the nodes
+//!
+//! All packets start with the packet type (u16, little-endian). The upper bits of the type are
+//! used as flags.
+
+/// The payload after the packet type and the IV is encrypted
+pub const FLAG_ENCRYPTED: u16 = 0x8000;
+/// The sender listens for an acknowledgement after the transmission
+pub const FLAG_ACK_REQUEST: u16 = 0x4000;
+/// Bits of the packet type without the flags
+pub const TYPE_MASK: u16 = 0x3FFF;
+
+/// Acknowledgement sent by the gateway
+pub const TYPE_ACK: u16 = 6;
+/// Packet forwarded by a relay
+pub const TYPE_RELAYED: u16 = 7;
+/// Request of a node to join the network
+pub const TYPE_JOIN_REQUEST: u16 = 8;
+/// Parameters assigned to a node by the gateway (encrypted with the network key)
+pub const TYPE_JOIN_ACCEPT: u16 = 9;
+/// Packet of a node identified by its short address (encrypted with the session key)
+pub const TYPE_SHORT_DATA: u16 = 10;
+/// Network time broadcast by the gateway
+pub const TYPE_TIME_BEACON: u16 = 11;
+/// Measurements of a node in the self-describing format of `tlv`
+pub const TYPE_SENSOR_DATA: u16 = 12;
+/// Batch of samples of a node identified by its short address (encrypted with the session key)
+pub const TYPE_SHORT_BATCH: u16 = 13;
+
+/// Length of the header of sensor data packets (type, device id, part id, index)
+pub const SENSOR_DATA_HEADER_LEN: usize = 18;
+
+pub const ACK_LEN: usize = 19;
+/// Length of the header of a relayed packet: packet type (u16), hop count (u8) and relay id (u64)
+pub const RELAY_HEADER_LEN: usize = 11;
+/// Number of times a packet is relayed at most
+pub const MAX_HOPS: u8 = 3;
+/// Longest random delay of a relay before forwarding a packet (ms)
+///
+/// The delay keeps relays that received the same packet from transmitting at the same time.
+pub const MAX_RELAY_BACKOFF_MS: u8 = 15;
+/// Longest time a relay adds to the way of a packet (µs; back-off and transmission of the longest
+/// packet)
+pub const MAX_RELAY_DELAY_MUS: u32 = MAX_RELAY_BACKOFF_MS as u32 * 1_000 + 2_500;
+/// Length of the plain text of a join request
+pub const JOIN_REQUEST_LEN: usize = 16;
+/// Length of the plain text of a join accept
+pub const JOIN_ACCEPT_LEN: usize = 36;
+pub const TIME_BEACON_LEN: usize = 13;
+/// Time between the start of the transmission of a time beacon and the end of its reception (µs)
+pub const TIME_BEACON_AIR_TIME_MUS: u64 = 300;
+/// Length of the header of a short address packet: packet type (u16), short address (u16) and IV
+pub const SHORT_HEADER_LEN: usize = 12;
+
+/// Acknowledgement of a packet
+///
+/// It echoes the RSSI the gateway measured, so that nodes can adapt their transmit power, and how
+/// far the packet was off the node's transmit slot, so that nodes can correct their timing.
+pub struct Ack {
+    pub device_id: u64,
+    pub index: u32,
+    /// RSSI sample of the acknowledged packet (magnitude in -dBm)
+    pub rssi: u8,
+    /// Reception time of the packet minus the start of the node's slot (µs; 0 without a slot)
+    pub timing: i32,
+}
+
+impl Ack {
+    pub fn to_bytes(&self) -> [u8; ACK_LEN] {
+        let mut data = [0u8; ACK_LEN];
+
+        data[0..2].copy_from_slice(&TYPE_ACK.to_le_bytes()[..]);
+        data[2..10].copy_from_slice(&self.device_id.to_le_bytes()[..]);
+        data[10..14].copy_from_slice(&self.index.to_le_bytes()[..]);
+        data[14] = self.rssi;
+        data[15..19].copy_from_slice(&self.timing.to_le_bytes()[..]);
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Ack> {
+        if data.len() != ACK_LEN || packet_type(data)? != TYPE_ACK {
+            return None;
+        }
+
+        Some(Ack {
+            device_id: read_u64(&data[2..10]),
+            index: read_u32(&data[10..14]),
+            rssi: data[14],
+            timing: read_u32(&data[15..19]) as i32,
+        })
+    }
+}
+
+/// Request of a node to join the network
+///
+/// The join request is sent as `[type | FLAG_ENCRYPTED][IV][encrypted plain text]` encrypted with
+/// the network key, so that only nodes knowing the key are enrolled. Only the plain text is
+/// handled here. The nonce is echoed in the join accept to tie it to the request.
+pub struct JoinRequest {
+    pub device_id: u64,
+    pub part_id: u32,
+    pub nonce: u32,
+}
+
+impl JoinRequest {
+    pub fn to_bytes(&self) -> [u8; JOIN_REQUEST_LEN] {
+        let mut data = [0u8; JOIN_REQUEST_LEN];
+
+        data[0..8].copy_from_slice(&self.device_id.to_le_bytes()[..]);
+        data[8..12].copy_from_slice(&self.part_id.to_le_bytes()[..]);
+        data[12..16].copy_from_slice(&self.nonce.to_le_bytes()[..]);
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<JoinRequest> {
+        if data.len() != JOIN_REQUEST_LEN {
+            return None;
+        }
+
+        Some(JoinRequest {
+            device_id: read_u64(&data[0..8]),
+            part_id: read_u32(&data[8..12]),
+            nonce: read_u32(&data[12..16]),
+        })
+    }
+}
+
+/// Parameters assigned to a node by the gateway
+///
+/// The join accept is sent as `[type | FLAG_ENCRYPTED][IV][encrypted plain text]`. Only the plain
+/// text is handled here.
+pub struct JoinAccept {
+    pub device_id: u64,
+    pub nonce: u32,
+    pub short_address: u16,
+    pub session_key: [u8; 16],
+    /// Reporting interval in s
+    pub interval: u16,
+    /// Start of the node's transmit slot within the reporting interval (ms)
+    pub slot_offset: u32,
+}
+
+impl JoinAccept {
+    pub fn to_bytes(&self) -> [u8; JOIN_ACCEPT_LEN] {
+        let mut data = [0u8; JOIN_ACCEPT_LEN];
+
+        data[0..8].copy_from_slice(&self.device_id.to_le_bytes()[..]);
+        data[8..12].copy_from_slice(&self.nonce.to_le_bytes()[..]);
+        data[12..14].copy_from_slice(&self.short_address.to_le_bytes()[..]);
+        data[14..30].copy_from_slice(&self.session_key[..]);
+        data[30..32].copy_from_slice(&self.interval.to_le_bytes()[..]);
+        data[32..36].copy_from_slice(&self.slot_offset.to_le_bytes()[..]);
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<JoinAccept> {
+        if data.len() != JOIN_ACCEPT_LEN {
+            return None;
+        }
+
+        let mut session_key = [0u8; 16];
+
+        session_key.copy_from_slice(&data[14..30]);
+
+        Some(JoinAccept {
+            device_id: read_u64(&data[0..8]),
+            nonce: read_u32(&data[8..12]),
+            short_address: u16::from_le_bytes([data[12], data[13]]),
+            session_key,
+            interval: u16::from_le_bytes([data[30], data[31]]),
+            slot_offset: read_u32(&data[32..36]),
+        })
+    }
+}
+
+/// Header of a packet sent with the short address of a node
+///
+/// The packet is sent as `[type][short address][IV][encrypted payload]`. The plain text of the
+/// payload starts with the index (u32), which is the base index for batches.
+pub struct ShortHeader<'a> {
+    pub package_type: u16,
+    pub short_address: u16,
+    pub iv: [u8; 8],
+    pub cipher: &'a [u8],
+}
+
+impl<'a> ShortHeader<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Option<ShortHeader<'a>> {
+        let package_type = packet_type(data)?;
+
+        match package_type & TYPE_MASK {
+            TYPE_SHORT_DATA | TYPE_SHORT_BATCH if data.len() > SHORT_HEADER_LEN => {}
+            _ => return None,
+        }
+
+        let mut iv = [0u8; 8];
+
+        iv.copy_from_slice(&data[4..12]);
+
+        Some(ShortHeader {
+            package_type,
+            short_address: u16::from_le_bytes([data[2], data[3]]),
+            iv,
+            cipher: &data[SHORT_HEADER_LEN..],
+        })
+    }
+}
+
+/// Network time broadcast by the gateway
+pub struct TimeBeacon {
+    /// Network time at the start of the transmission (µs)
+    pub time: u64,
+    /// Time between two beacons (s)
+    pub interval: u16,
+    /// The network time has been set by the host (µs since the Unix epoch); otherwise it is the
+    /// time since the start of the gateway
+    pub absolute: bool,
+}
+
+impl TimeBeacon {
+    pub fn to_bytes(&self) -> [u8; TIME_BEACON_LEN] {
+        let mut data = [0u8; TIME_BEACON_LEN];
+
+        data[0..2].copy_from_slice(&TYPE_TIME_BEACON.to_le_bytes()[..]);
+        data[2..10].copy_from_slice(&self.time.to_le_bytes()[..]);
+        data[10..12].copy_from_slice(&self.interval.to_le_bytes()[..]);
+        data[12] = self.absolute as u8;
+
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<TimeBeacon> {
+        if data.len() != TIME_BEACON_LEN || packet_type(data)? != TYPE_TIME_BEACON {
+            return None;
+        }
+
+        Some(TimeBeacon {
+            time: read_u64(&data[2..10]),
+            interval: u16::from_le_bytes([data[10], data[11]]),
+            absolute: data[12] & 0x01 == 0x01,
+        })
+    }
+}
+
+/// Packet forwarded by a relay
+///
+/// Only the last relay is recorded; the original packet is carried unchanged.
+pub struct Relayed<'a> {
+    pub hops: u8,
+    pub relay_id: u64,
+    pub inner: &'a [u8],
+}
+
+impl<'a> Relayed<'a> {
+    pub fn from_bytes(data: &'a [u8]) -> Option<Relayed<'a>> {
+        if data.len() <= RELAY_HEADER_LEN || packet_type(data)? != TYPE_RELAYED {
+            return None;
+        }
+
+        Some(Relayed {
+            hops: data[2],
+            relay_id: read_u64(&data[3..11]),
+            inner: &data[RELAY_HEADER_LEN..],
+        })
+    }
+}
+
+/// Wrap a packet for relaying.
+///
+/// A packet that has been relayed before is unwrapped first and its hop count is incremented.
+/// Returns the length of the packet written to `buffer` or `None` if the packet must not be
+/// relayed (any more) or does not fit into the buffer.
+pub fn relay(data: &[u8], relay_id: u64, buffer: &mut [u8]) -> Option<usize> {
+    let (hops, inner) = match Relayed::from_bytes(data) {
+        Some(relayed) => (relayed.hops.checked_add(1)?, relayed.inner),
+        None => (1, data),
+    };
+    let len = RELAY_HEADER_LEN + inner.len();
+
+    if hops > MAX_HOPS || !is_relayable(inner) || len > buffer.len() {
+        return None;
+    }
+
+    buffer[0..2].copy_from_slice(&TYPE_RELAYED.to_le_bytes()[..]);
+    buffer[2] = hops;
+    buffer[3..11].copy_from_slice(&relay_id.to_le_bytes()[..]);
+    buffer[RELAY_HEADER_LEN..len].copy_from_slice(inner);
+
+    Some(len)
+}
+
+/// Check whether a packet may be relayed.
+///
+/// The packets of the nodes are relayed towards the gateway and the packets of the gateway
+/// (acknowledgements, join accepts and time beacons) towards the nodes.
+pub fn is_relayable(data: &[u8]) -> bool {
+    match packet_type(data) {
+        Some(package_type) => package_type & TYPE_MASK != TYPE_RELAYED,
+        None => false,
+    }
+}
+
+/// Check whether a packet is sent by the gateway to the nodes.
+pub fn is_downlink(data: &[u8]) -> bool {
+    match packet_type(data) {
+        Some(package_type) => matches!(
+            package_type & TYPE_MASK,
+            TYPE_ACK | TYPE_JOIN_ACCEPT | TYPE_TIME_BEACON
+        ),
+        None => false,
+    }
+}
+
+/// Get the original packet of a packet that may have been relayed.
+pub fn unwrap_relayed(data: &[u8]) -> &[u8] {
+    match Relayed::from_bytes(data) {
+        Some(relayed) => relayed.inner,
+        None => data,
+    }
+}
+
+/// Time between the start of the transmission of a packet carrying a time beacon (relayed or not)
+/// and the end of its reception (µs).
+pub fn beacon_air_time_mus(len: usize) -> u64 {
+    // 8 µs per byte at 1 Mbit/s
+    TIME_BEACON_AIR_TIME_MUS + len.saturating_sub(TIME_BEACON_LEN) as u64 * 8
+}
+
+/// Get an identity of a packet for detecting duplicates.
+///
+/// Plain packets are identified by their device id and index. Encrypted packets, including join
+/// requests, can only be identified by their IV, as the device id is part of the encrypted
+/// payload. Packets sent with a short address are identified by their IV and short address.
+///
+/// Acknowledgements are identified by the device id (or short address) and index they acknowledge
+/// and time beacons by their network time rounded to s, as relays adjust the time. As the
+/// identities of packets of the gateway can match those of the packets they answer, they must
+/// only be compared among each other.
+pub fn packet_identity(data: &[u8]) -> Option<(u64, u32)> {
+    if let Some(relayed) = Relayed::from_bytes(data) {
+        return packet_identity(relayed.inner);
+    }
+
+    if let Some(header) = ShortHeader::from_bytes(data) {
+        return Some((u64::from_le_bytes(header.iv), header.short_address as u32));
+    }
+
+    let package_type = packet_type(data)?;
+
+    if package_type & FLAG_ENCRYPTED == FLAG_ENCRYPTED {
+        if data.len() < 10 {
+            return None;
+        }
+
+        return Some((read_u64(&data[2..10]), 0));
+    }
+
+    match package_type & TYPE_MASK {
+        3 | 4 | 5 | TYPE_SENSOR_DATA if data.len() >= 18 => {
+            Some((read_u64(&data[2..10]), read_u32(&data[14..18])))
+        }
+        TYPE_ACK => Ack::from_bytes(data).map(|ack| (ack.device_id, ack.index)),
+        TYPE_TIME_BEACON => TimeBeacon::from_bytes(data)
+            .map(|beacon| ((beacon.time + 500_000) / 1_000_000, TYPE_TIME_BEACON as u32)),
+        _ => None,
+    }
+}
+
+/// Number of packet identities remembered by `RecentPackets`
+pub const RECENT_PACKETS_LEN: usize = 32;
+
+/// Packets seen recently, for detecting duplicates
+pub struct RecentPackets {
+    entries: [Option<((u64, u32), u64)>; RECENT_PACKETS_LEN],
+    next: usize,
+    window: u64,
+}
+
+impl RecentPackets {
+    /// # Arguments
+    ///
+    /// * `window` - time (in the unit of the time passed to `insert`) a packet is remembered
+    ///
+    pub fn new(window: u64) -> RecentPackets {
+        RecentPackets {
+            entries: [None; RECENT_PACKETS_LEN],
+            next: 0,
+            window,
+        }
+    }
+
+    /// Remember a packet.
+    ///
+    /// Returns `false` if the packet has been seen within the window before.
+    pub fn insert(&mut self, identity: (u64, u32), now: u64) -> bool {
+        let window = self.window;
+        let seen = self.entries.iter().any(|entry| match entry {
+            Some((seen_identity, time)) => {
+                *seen_identity == identity && now.saturating_sub(*time) < window
+            }
+            None => false,
+        });
+
+        if seen {
+            return false;
+        }
+
+        self.entries[self.next] = Some((identity, now));
+        self.next = (self.next + 1) % RECENT_PACKETS_LEN;
+
+        true
+    }
+}
+
+/// Get the packet type including the flags.
+pub fn packet_type(data: &[u8]) -> Option<u16> {
+    if data.len() < 2 {
+        return None;
+    }
+
+    Some(u16::from_le_bytes([data[0], data[1]]))
+}
+
+/// Get the device id and the index of a packet requesting an acknowledgement.
+///
+/// Packets requesting an acknowledgement must not be encrypted and start with the device id
+/// (u64), the part id (u32) and the index (u32) after the packet type.
+pub fn ack_request(data: &[u8]) -> Option<(u64, u32)> {
+    let package_type = packet_type(data)?;
+
+    if package_type & (FLAG_ACK_REQUEST | FLAG_ENCRYPTED) != FLAG_ACK_REQUEST || data.len() < 18 {
+        return None;
+    }
+
+    Some((read_u64(&data[2..10]), read_u32(&data[14..18])))
+}
+
+pub(crate) fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+pub(crate) fn read_u64(data: &[u8]) -> u64 {
+    u64::from_le_bytes([
+        data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7],
+    ])
+}
@@ -0,0 +1,46 @@
+use nrf52810_pac as pac;
+
+/// Number of programmable channels (the remaining channels are pre-programmed)
+pub const CHANNEL_COUNT: usize = 20;
+
+pub struct Ppi {
+    ppi: pac::PPI,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Channel,
+}
+
+impl Ppi {
+    pub fn new(ppi: pac::PPI) -> Ppi {
+        Ppi { ppi: ppi }
+    }
+
+    /// Connect an event to a task
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - programmable channel; allowed values are < `CHANNEL_COUNT`
+    /// * `event` - address of the event register
+    /// * `task` - address of the task register
+    ///
+    pub fn connect(&mut self, channel: usize, event: u32, task: u32) -> Result<(), Error> {
+        if channel >= CHANNEL_COUNT {
+            return Err(Error::Channel);
+        }
+
+        self.ppi.ch[channel].eep.write(|w| unsafe { w.bits(event) });
+        self.ppi.ch[channel].tep.write(|w| unsafe { w.bits(task) });
+
+        Ok(())
+    }
+
+    pub fn enable(&mut self, channel: usize) {
+        self.ppi.chenset.write(|w| unsafe { w.bits(1 << channel) });
+    }
+
+    pub fn disable(&mut self, channel: usize) {
+        self.ppi.chenclr.write(|w| unsafe { w.bits(1 << channel) });
+    }
+}
@@ -1,8 +1,64 @@
+use core::sync::atomic::{compiler_fence, Ordering};
 use nrf52810_hal::pac;
 
+/// Largest payload that fits into a packet (the length field is 8 bits wide)
+pub const MAX_PAYLOAD_LEN: usize = 255;
+/// Size of a packet buffer (length field and payload)
+pub const PACKET_BUFFER_LEN: usize = MAX_PAYLOAD_LEN + 1;
+/// Number of buffers used for reception
+pub const RX_BUFFER_COUNT: usize = 3;
+
+/// Number of logical addresses the radio can listen on
+pub const LOGICAL_ADDRESS_COUNT: usize = 8;
+
+/// Buffers the radio receives into; they must be `'static` as EasyDMA writes to them in the background
+pub type RxBuffers = [[u8; PACKET_BUFFER_LEN]; RX_BUFFER_COUNT];
+
 pub struct Radio {
     radio: pac::RADIO,
-    packet: [u8; 258],
+    tx_power: TxPower,
+    tx_packet: [u8; PACKET_BUFFER_LEN],
+    rx_buffers: Option<&'static mut RxBuffers>,
+    rx_slots: [RxSlot; RX_BUFFER_COUNT],
+    rx_current: Option<usize>,
+    rx_sequence: u32,
+}
+
+#[derive(Clone, Copy)]
+struct FrameInfo {
+    sequence: u32,
+    timestamp: u64,
+    rssi: u8,
+    crc_ok: bool,
+    address_match: u8,
+}
+
+#[derive(Clone, Copy)]
+enum RxSlot {
+    Free,
+    Receiving,
+    Ready(FrameInfo),
+    Borrowed(FrameInfo),
+}
+
+/// Logical addresses used for reception
+///
+/// Logical address 0 consists of `base0` and `prefixes[0]`; the logical addresses 1 to 7 share
+/// `base1` and use their respective prefix.
+pub struct AddressConfig {
+    pub base0: u32,
+    pub base1: u32,
+    pub prefixes: [u8; LOGICAL_ADDRESS_COUNT],
+    /// Bit mask of the logical addresses to listen on
+    pub enabled: u8,
+}
+
+/// Handle to a received frame
+///
+/// The buffer holding the frame is not used for reception until the token is handed back with
+/// `release_frame`.
+pub struct FrameToken {
+    slot: usize,
 }
 
 pub enum Mode {
@@ -12,16 +68,97 @@ pub enum Mode {
     Nrf2Mbit,
 }
 
+/// Transmit power levels supported by the nRF52810
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TxPower {
+    Neg40dBm,
+    Neg20dBm,
+    Neg16dBm,
+    Neg12dBm,
+    Neg8dBm,
+    Neg4dBm,
+    ZerodBm,
+    Pos3dBm,
+    Pos4dBm,
+}
+
+impl TxPower {
+    pub fn dbm(&self) -> i8 {
+        match self {
+            TxPower::Neg40dBm => -40,
+            TxPower::Neg20dBm => -20,
+            TxPower::Neg16dBm => -16,
+            TxPower::Neg12dBm => -12,
+            TxPower::Neg8dBm => -8,
+            TxPower::Neg4dBm => -4,
+            TxPower::ZerodBm => 0,
+            TxPower::Pos3dBm => 3,
+            TxPower::Pos4dBm => 4,
+        }
+    }
+
+    /// Get the next higher level; the highest level is returned unchanged.
+    pub fn higher(&self) -> TxPower {
+        match self {
+            TxPower::Neg40dBm => TxPower::Neg20dBm,
+            TxPower::Neg20dBm => TxPower::Neg16dBm,
+            TxPower::Neg16dBm => TxPower::Neg12dBm,
+            TxPower::Neg12dBm => TxPower::Neg8dBm,
+            TxPower::Neg8dBm => TxPower::Neg4dBm,
+            TxPower::Neg4dBm => TxPower::ZerodBm,
+            TxPower::ZerodBm => TxPower::Pos3dBm,
+            TxPower::Pos3dBm | TxPower::Pos4dBm => TxPower::Pos4dBm,
+        }
+    }
+
+    /// Get the next lower level; the lowest level is returned unchanged.
+    pub fn lower(&self) -> TxPower {
+        match self {
+            TxPower::Neg40dBm | TxPower::Neg20dBm => TxPower::Neg40dBm,
+            TxPower::Neg16dBm => TxPower::Neg20dBm,
+            TxPower::Neg12dBm => TxPower::Neg16dBm,
+            TxPower::Neg8dBm => TxPower::Neg12dBm,
+            TxPower::Neg4dBm => TxPower::Neg8dBm,
+            TxPower::ZerodBm => TxPower::Neg4dBm,
+            TxPower::Pos3dBm => TxPower::ZerodBm,
+            TxPower::Pos4dBm => TxPower::Pos3dBm,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Frequency,
+    PayloadTooLong,
+    NoRxBuffer,
+    /// frames taken with `take_frame` have not been released yet
+    FramesNotReleased,
+}
+
+/// A packet received by the radio
+///
+/// The payload covers exactly the number of bytes announced in the length field.
+pub struct ReceivedFrame<'a> {
+    pub payload: &'a [u8],
+    /// RSSI sample (magnitude of the received signal strength in -dBm)
+    pub rssi: u8,
+    pub crc_ok: bool,
+    /// Logical address the packet was received on
+    pub address_match: u8,
+    /// Time of arrival as passed to `complete_reception`
+    pub timestamp: u64,
 }
 
 impl Radio {
     pub fn new(radio: pac::RADIO) -> Radio {
         Radio {
             radio: radio,
-            packet: [0; 258],
+            tx_power: TxPower::Pos4dBm,
+            tx_packet: [0; PACKET_BUFFER_LEN],
+            rx_buffers: None,
+            rx_slots: [RxSlot::Free; RX_BUFFER_COUNT],
+            rx_current: None,
+            rx_sequence: 0,
         }
     }
 
@@ -63,6 +200,32 @@ impl Radio {
         }
     }
 
+    /// Set the transmit power
+    ///
+    /// The level is kept across `init_transmission` and power cycles of the radio.
+    pub fn set_tx_power(&mut self, power: TxPower) {
+        self.tx_power = power;
+        self.write_tx_power();
+    }
+
+    pub fn get_tx_power(&self) -> TxPower {
+        self.tx_power
+    }
+
+    fn write_tx_power(&mut self) {
+        match self.tx_power {
+            TxPower::Neg40dBm => self.radio.txpower.write(|w| w.txpower().neg40d_bm()),
+            TxPower::Neg20dBm => self.radio.txpower.write(|w| w.txpower().neg20d_bm()),
+            TxPower::Neg16dBm => self.radio.txpower.write(|w| w.txpower().neg16d_bm()),
+            TxPower::Neg12dBm => self.radio.txpower.write(|w| w.txpower().neg12d_bm()),
+            TxPower::Neg8dBm => self.radio.txpower.write(|w| w.txpower().neg8d_bm()),
+            TxPower::Neg4dBm => self.radio.txpower.write(|w| w.txpower().neg4d_bm()),
+            TxPower::ZerodBm => self.radio.txpower.write(|w| w.txpower()._0d_bm()),
+            TxPower::Pos3dBm => self.radio.txpower.write(|w| w.txpower().pos3d_bm()),
+            TxPower::Pos4dBm => self.radio.txpower.write(|w| w.txpower().pos4d_bm()),
+        }
+    }
+
     pub fn init_transmission(&mut self) {
         // POWER
         // 1 (default)
@@ -113,8 +276,8 @@ impl Radio {
             .write(|w| unsafe { w.txaddress().bits(0) });
 
         // TXPOWER
-        // TXPOWER: +4 dB
-        self.radio.txpower.write(|w| w.txpower().pos4d_bm());
+        // TXPOWER: as set with `set_tx_power` (default: +4 dB)
+        self.write_tx_power();
 
         // CRCCNF
         // LEN: length => 3
@@ -136,28 +299,52 @@ impl Radio {
             .write(|w| w.ready_start().bit(true).end_disable().bit(true));
     }
 
-    pub fn start_transmission(&mut self, data: &[&[u8]]) {
+    /// Copy the data into the packet buffer and start the transmission.
+    ///
+    /// The parts are sent back to back as a single payload. The length field is set to the number
+    /// of payload bytes, which is the same convention `received_frame` uses.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - Parts of the payload; their combined length must not exceed `MAX_PAYLOAD_LEN`
+    ///
+    pub fn start_transmission(&mut self, data: &[&[u8]]) -> Result<(), Error> {
+        let len: usize = data.iter().map(|part| part.len()).sum();
+
+        if len > MAX_PAYLOAD_LEN {
+            return Err(Error::PayloadTooLong);
+        }
+
         // copy data into buffer
-        let mut len = 0;
+        let mut pos = 1;
 
         for part in data {
-            for byte in *part {
-                self.packet[len + 1] = *byte;
-                len += 1;
-            }
+            self.tx_packet[pos..pos + part.len()].copy_from_slice(part);
+            pos += part.len();
         }
 
-        self.packet[0] = (len + 1) as u8;
-        // for (a, b) in self.packet[0..data.len()].iter_mut().zip(data.iter()) { *a = *b; }
+        self.tx_packet[0] = len as u8;
 
         // enable "disabled" interrupt
         self.radio.intenset.write(|w| w.disabled().bit(true));
         // set packet pointer
         self.radio
             .packetptr
-            .write(|w| unsafe { w.packetptr().bits((&self.packet as *const u8) as u32) });
+            .write(|w| unsafe { w.packetptr().bits(self.tx_packet.as_ptr() as u32) });
+        compiler_fence(Ordering::SeqCst);
         // start transmission task
         self.radio.tasks_txen.write(|w| w.tasks_txen().bit(true));
+
+        Ok(())
+    }
+
+    /// Stop an ongoing reception or transmission.
+    ///
+    /// The "disabled" event is generated once the radio has stopped.
+    pub fn disable(&self) {
+        self.radio
+            .tasks_disable
+            .write(|w| w.tasks_disable().trigger());
     }
 
     pub fn init_reception(&self) {
@@ -265,7 +452,83 @@ impl Radio {
         });
     }
 
-    pub fn start_reception(&self) {
+    /// Set the logical addresses to listen on.
+    ///
+    /// Must be called after `init_reception` as it sets up a default configuration.
+    pub fn set_rx_addresses(&mut self, config: &AddressConfig) {
+        self.radio
+            .base0
+            .write(|w| unsafe { w.base0().bits(config.base0) });
+        self.radio
+            .base1
+            .write(|w| unsafe { w.base1().bits(config.base1) });
+        self.radio.prefix0.write(|w| unsafe {
+            w.ap0()
+                .bits(config.prefixes[0])
+                .ap1()
+                .bits(config.prefixes[1])
+                .ap2()
+                .bits(config.prefixes[2])
+                .ap3()
+                .bits(config.prefixes[3])
+        });
+        self.radio.prefix1.write(|w| unsafe {
+            w.ap4()
+                .bits(config.prefixes[4])
+                .ap5()
+                .bits(config.prefixes[5])
+                .ap6()
+                .bits(config.prefixes[6])
+                .ap7()
+                .bits(config.prefixes[7])
+        });
+        self.radio
+            .rxaddresses
+            .write(|w| unsafe { w.bits(config.enabled as u32) });
+    }
+
+    /// Hand over the buffers used for reception.
+    ///
+    /// Fails while frames taken with `take_frame` have not been released, as their tokens refer to
+    /// the current buffers.
+    pub fn set_rx_buffers(&mut self, buffers: &'static mut RxBuffers) -> Result<(), Error> {
+        if self
+            .rx_slots
+            .iter()
+            .any(|slot| matches!(slot, RxSlot::Borrowed(_)))
+        {
+            return Err(Error::FramesNotReleased);
+        }
+
+        self.rx_buffers = Some(buffers);
+        self.rx_slots = [RxSlot::Free; RX_BUFFER_COUNT];
+        self.rx_current = None;
+
+        Ok(())
+    }
+
+    /// Start receiving into a free buffer.
+    ///
+    /// Fails if no buffers have been set or if all of them hold frames that have not been released.
+    pub fn start_reception(&mut self) -> Result<(), Error> {
+        let slot = match self.rx_current {
+            Some(slot) => slot,
+            None => self
+                .rx_slots
+                .iter()
+                .position(|slot| matches!(slot, RxSlot::Free))
+                .ok_or(Error::NoRxBuffer)?,
+        };
+        let buffer = match self.rx_buffers.as_mut() {
+            Some(buffers) => &mut buffers[slot],
+            None => return Err(Error::NoRxBuffer),
+        };
+
+        // clear the length field so that a previous packet is not reported again
+        buffer[0] = 0;
+        self.rx_slots[slot] = RxSlot::Receiving;
+        self.rx_current = Some(slot);
+
         self.radio.intenset.write(|w| {
             w
                 //  .ready().bit(true)
@@ -279,9 +542,99 @@ impl Radio {
         // set packet pointer
         self.radio
             .packetptr
-            .write(|w| unsafe { w.packetptr().bits((&self.packet as *const u8) as u32) });
+            .write(|w| unsafe { w.packetptr().bits(buffer.as_ptr() as u32) });
+        compiler_fence(Ordering::SeqCst);
 
         self.radio.tasks_rxen.write(|w| w.tasks_rxen().bit(true));
+
+        Ok(())
+    }
+
+    /// Finish the reception into the current buffer.
+    ///
+    /// To be called on the "disabled" event. A packet with a payload is queued for `take_frame`
+    /// and the buffer is not received into again until the frame has been released.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - time of arrival of the packet in the time base of the caller
+    ///
+    pub fn complete_reception(&mut self, timestamp: u64) {
+        compiler_fence(Ordering::SeqCst);
+
+        let slot = match self.rx_current.take() {
+            Some(slot) => slot,
+            None => return,
+        };
+        let len = match self.rx_buffers.as_ref() {
+            Some(buffers) => buffers[slot][0] as usize,
+            None => 0,
+        };
+
+        self.rx_slots[slot] = if len > 0 {
+            self.rx_sequence = self.rx_sequence.wrapping_add(1);
+
+            RxSlot::Ready(FrameInfo {
+                sequence: self.rx_sequence,
+                timestamp: timestamp,
+                rssi: self.rssi(),
+                crc_ok: self.crc_ok(),
+                address_match: self.address_match(),
+            })
+        } else {
+            RxSlot::Free
+        };
+    }
+
+    /// Take the oldest received frame that has not been taken yet.
+    pub fn take_frame(&mut self) -> Option<FrameToken> {
+        let mut oldest: Option<(usize, FrameInfo)> = None;
+
+        for (slot, state) in self.rx_slots.iter().enumerate() {
+            if let RxSlot::Ready(info) = state {
+                let is_older = match oldest {
+                    Some((_, oldest_info)) => {
+                        info.sequence.wrapping_sub(oldest_info.sequence) > u32::MAX / 2
+                    }
+                    None => true,
+                };
+
+                if is_older {
+                    oldest = Some((slot, *info));
+                }
+            }
+        }
+
+        let (slot, info) = oldest?;
+
+        self.rx_slots[slot] = RxSlot::Borrowed(info);
+
+        Some(FrameToken { slot })
+    }
+
+    /// Get the frame a token refers to.
+    pub fn frame(&self, token: &FrameToken) -> ReceivedFrame {
+        // the slot stays borrowed until the token is released (see `set_rx_buffers`)
+        let info = match self.rx_slots[token.slot] {
+            RxSlot::Borrowed(info) => info,
+            _ => unreachable!(),
+        };
+        // a token can only exist if buffers have been set
+        let buffer = &self.rx_buffers.as_ref().unwrap()[token.slot];
+        let len = buffer[0] as usize;
+
+        ReceivedFrame {
+            payload: &buffer[1..len + 1],
+            rssi: info.rssi,
+            crc_ok: info.crc_ok,
+            address_match: info.address_match,
+            timestamp: info.timestamp,
+        }
+    }
+
+    /// Hand the buffer of a frame back for reception.
+    pub fn release_frame(&mut self, token: FrameToken) {
+        self.rx_slots[token.slot] = RxSlot::Free;
     }
 
     pub fn is_ready_set(&self) -> bool {
@@ -306,6 +659,11 @@ impl Radio {
             == nrf52810_pac::radio::events_address::EVENTS_ADDRESS_A::GENERATED
     }
 
+    /// Address of the "address" event for connecting it through PPI.
+    pub fn event_address_endpoint(&self) -> u32 {
+        &self.radio.events_address as *const _ as u32
+    }
+
     pub fn clear_address(&self) {
         self.radio.intenclr.write(|w| w.address().bit(true));
     }
@@ -510,11 +868,7 @@ impl Radio {
         self.radio.rxmatch.read().rxmatch().bits()
     }
 
-    pub fn payload(&self) -> Option<&[u8]> {
-        if self.packet[0] > 0 {
-            Some(&self.packet[1..self.packet[0] as usize])
-        } else {
-            None
-        }
+    pub fn crc_ok(&self) -> bool {
+        self.radio.crcstatus.read().crcstatus().is_crcok()
     }
 }
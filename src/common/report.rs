@@ -0,0 +1,80 @@
+//! Decision whether a measurement is reported
+//!
+//! In the adaptive mode a node only reports a measurement if a value moved more than a threshold
+//! since the last report or if the heartbeat timeout expired.
+
+/// Reason for reporting a measurement
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// reported at every interval
+    Interval = 0,
+    /// a value moved more than its threshold since the last report (or there was no report yet)
+    Change = 1,
+    /// nothing changed, but the heartbeat timeout expired
+    Heartbeat = 2,
+}
+
+pub struct ReportFilter {
+    temperature_threshold: f32,
+    humidity_threshold: f32,
+    /// longest time between two reports
+    heartbeat: u64,
+    /// time, temperature and humidity of the last report
+    last: Option<(u64, f32, f32)>,
+}
+
+impl ReportFilter {
+    /// Create a new filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `temperature_threshold` - change of the temperature that is reported (°C)
+    /// * `humidity_threshold` - change of the humidity that is reported (%RH)
+    /// * `heartbeat` - longest time between two reports (µs)
+    ///
+    pub fn new(
+        temperature_threshold: f32,
+        humidity_threshold: f32,
+        heartbeat: u64,
+    ) -> ReportFilter {
+        ReportFilter {
+            temperature_threshold,
+            humidity_threshold,
+            heartbeat,
+            last: None,
+        }
+    }
+
+    /// Check whether a measurement is reported.
+    ///
+    /// A reported measurement becomes the reference for the following ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `time` - time of the measurement (µs)
+    ///
+    pub fn check(&mut self, time: u64, temperature: f32, humidity: f32) -> Option<Reason> {
+        let reason = match self.last {
+            None => Reason::Change,
+            Some((_, last_temperature, last_humidity))
+                if exceeds(temperature - last_temperature, self.temperature_threshold)
+                    || exceeds(humidity - last_humidity, self.humidity_threshold) =>
+            {
+                Reason::Change
+            }
+            Some((last_time, _, _)) if time.saturating_sub(last_time) >= self.heartbeat => {
+                Reason::Heartbeat
+            }
+            Some(_) => return None,
+        };
+
+        self.last = Some((time, temperature, humidity));
+
+        Some(reason)
+    }
+}
+
+/// `f32::abs` is not available without `std`.
+fn exceeds(difference: f32, threshold: f32) -> bool {
+    difference > threshold || difference < -threshold
+}
@@ -65,4 +65,5 @@ impl Rng<Active> {
 fn RNG() {
     let device = unsafe { pac::Peripherals::steal() };
     device.RNG.intenclr.write(|w| w.valrdy().clear());
+    super::rng2::WAKER.wake();
 }
@@ -1,7 +1,11 @@
-use core::future::Future;
-use core::task::{Context, Poll};
+use super::executor;
+use core::task::Poll;
 use nrf52810_pac as pac;
 
+/// Waker of the value waited for (woken by the interrupt handler in `rng`)
+pub(crate) static WAKER: executor::WakerSlot = executor::WakerSlot::new();
+
+/// Random number generator for the executor
 pub struct Rng2 {
     rng: pac::RNG,
 }
@@ -13,19 +17,32 @@ impl Rng2 {
 
         Rng2 { rng: rng }
     }
-}
 
-impl Future for Rng2 {
-    type Output = u8;
-
-    fn poll(
-        self: core::pin::Pin<&mut Self>,
-        _: &mut Context<'_>,
-    ) -> Poll<<Self as Future>::Output> {
-        if self.rng.events_valrdy.read().events_valrdy().is_generated() {
-            Poll::Ready(self.rng.value.read().value().bits())
-        } else {
-            Poll::Pending
-        }
+    /// Get a random value without blocking the executor.
+    pub async fn value(&mut self) -> u8 {
+        self.rng
+            .events_valrdy
+            .write(|w| w.events_valrdy().not_generated());
+        self.rng.tasks_start.write(|w| w.tasks_start().trigger());
+
+        let rng = &self.rng;
+        let value = executor::poll_fn(|cx| {
+            WAKER.register(cx.waker());
+            // the interrupt handler disables the interrupt after waking the future
+            rng.intenset.write(|w| w.valrdy().set());
+
+            match rng.events_valrdy.read().events_valrdy().is_generated() {
+                true => Poll::Ready(rng.value.read().value().bits()),
+                false => Poll::Pending,
+            }
+        })
+        .await;
+
+        self.rng.tasks_stop.write(|w| w.tasks_stop().trigger());
+        self.rng
+            .events_valrdy
+            .write(|w| w.events_valrdy().not_generated());
+
+        value
     }
 }
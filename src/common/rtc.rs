@@ -1,29 +1,130 @@
+//! Real time counter
+//!
+//! The counter runs from the 32.768 kHz low frequency clock; `start_monotonic` turns it into a
+//! continuous time base with 64-bit ticks by counting the overflows of the 24-bit counter in the
+//! interrupt handler. Alarms are set on the compare channels of this time base; several periodic
+//! activities can share one channel through a `TimerQueue`.
+//!
+//! The handler of RTC0 is defined here. RTC1 is bound by the RTIC applications (the gateway and the
+//! relay, which uses `MonotonicRtc1`), so an application using RTC1 with this driver must bind
+//! `RTC1` and call `on_interrupt::<pac::RTC1>()` from its handler; otherwise the overflows are not
+//! counted.
+
+use super::executor;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::Poll;
 use nrf52810_pac as pac;
 use pac::interrupt;
 
-pub struct Rtc<'a> {
-    rtc: &'a mut pac::RTC0,
+/// Largest prescaler (12 bits)
+pub const MAX_PRESCALER: u16 = 0xFFF;
+/// Largest compare value (24 bits)
+pub const MAX_COMPARE: u32 = 0xFF_FFFF;
+/// Number of timers a `TimerQueue` holds
+pub const QUEUE_LEN: usize = 8;
+
+// bits of the INTEN and EVTEN registers
+const INTEN_OVRFLW: u32 = 1 << 1;
+const INTEN_COMPARE0: u32 = 1 << 16;
+
+/// Number of counter overflows counted by the interrupt handlers
+static OVERFLOWS0: AtomicU32 = AtomicU32::new(0);
+static OVERFLOWS1: AtomicU32 = AtomicU32::new(0);
+/// Wakers of the alarms waited for
+static WAKER0: executor::WakerSlot = executor::WakerSlot::new();
+static WAKER1: executor::WakerSlot = executor::WakerSlot::new();
+
+/// RTC peripheral (RTC0 or RTC1)
+pub trait Instance: core::ops::Deref<Target = pac::rtc0::RegisterBlock> {
+    const INTERRUPT: pac::Interrupt;
+    /// Number of compare channels
+    const CHANNEL_COUNT: usize;
+
+    fn registers() -> &'static pac::rtc0::RegisterBlock;
+    fn overflows() -> &'static AtomicU32;
+    fn waker() -> &'static executor::WakerSlot;
+}
+
+macro_rules! impl_instance {
+    ($rtc:ident, $channels:expr, $overflows:ident, $waker:ident) => {
+        impl Instance for pac::$rtc {
+            const INTERRUPT: pac::Interrupt = pac::Interrupt::$rtc;
+            const CHANNEL_COUNT: usize = $channels;
+
+            fn registers() -> &'static pac::rtc0::RegisterBlock {
+                unsafe { &*pac::$rtc::ptr() }
+            }
+
+            fn overflows() -> &'static AtomicU32 {
+                &$overflows
+            }
+
+            fn waker() -> &'static executor::WakerSlot {
+                &$waker
+            }
+        }
+    };
+}
+
+impl_instance!(RTC0, 3, OVERFLOWS0, WAKER0);
+impl_instance!(RTC1, 4, OVERFLOWS1, WAKER1);
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// the prescaler does not fit into 12 bits
+    Prescaler,
+    /// the compare value does not fit into 24 bits
+    Compare,
+    /// the channel does not exist
+    Channel,
+    /// all timers of the queue are in use
+    QueueFull,
 }
 
-impl<'a> Rtc<'a> {
-    pub fn new(rtc: &'a mut pac::RTC0, nvic: &mut pac::NVIC) -> Self {
+pub struct Rtc<'a, T: Instance = pac::RTC0> {
+    rtc: &'a mut T,
+}
+
+impl<'a, T: Instance> Rtc<'a, T> {
+    pub fn new(rtc: &'a mut T, nvic: &mut pac::NVIC) -> Self {
         #[allow(deprecated)]
-        nvic.enable(pac::interrupt::RTC0);
-        // unsafe { cortex_m::peripheral::NVIC::unmask(pac::interrupt::RTC0) };
+        nvic.enable(T::INTERRUPT);
 
         Rtc { rtc }
     }
 
-    pub fn set_prescaler(&mut self, prescaler: u16) {
-        // TODO: check that the prescaler is <= 12 bits.
+    /// Set the prescaler; the counter runs at 32768 Hz / (prescaler + 1).
+    pub fn set_prescaler(&mut self, prescaler: u16) -> Result<(), Error> {
+        if prescaler > MAX_PRESCALER {
+            return Err(Error::Prescaler);
+        }
+
         self.rtc
             .prescaler
             .write(|w| unsafe { w.prescaler().bits(prescaler) });
+
+        Ok(())
     }
 
-    pub fn set_compare(&mut self, compare: u32) {
-        // TODO: check that the compare value is <= 24 bits.
-        self.rtc.cc[0].write(|w| unsafe { w.compare().bits(compare) });
+    /// Set the compare value of channel 0 for `start` and `wait`.
+    pub fn set_compare(&mut self, compare: u32) -> Result<(), Error> {
+        self.set_channel_compare(0, compare)
+    }
+
+    /// Set the compare value of a channel.
+    pub fn set_channel_compare(&mut self, channel: usize, compare: u32) -> Result<(), Error> {
+        if channel >= T::CHANNEL_COUNT {
+            return Err(Error::Channel);
+        }
+
+        if compare > MAX_COMPARE {
+            return Err(Error::Compare);
+        }
+
+        self.rtc.cc[channel].write(|w| unsafe { w.compare().bits(compare) });
+
+        Ok(())
     }
 
     pub fn start(&self) {
@@ -34,6 +135,143 @@ impl<'a> Rtc<'a> {
         self.rtc.tasks_start.write(|w| w.tasks_start().trigger());
     }
 
+    /// Start the counter as a continuous time base.
+    ///
+    /// Overflows of the 24-bit counter are counted in the interrupt handler to extend it to 64 bits.
+    pub fn start_monotonic(&mut self) {
+        unsafe { reset::<T>() };
+    }
+
+    /// Get the number of ticks since `start_monotonic`.
+    pub fn ticks(&self) -> u64 {
+        ticks::<T>()
+    }
+
+    /// Get the time since `start_monotonic` in µs.
+    pub fn micros(&self) -> u64 {
+        let prescaler = self.rtc.prescaler.read().prescaler().bits() as u64;
+        // ticks of the 32.768 kHz low frequency clock
+        let lf_ticks = self.ticks() * (prescaler + 1);
+
+        (lf_ticks >> 15) * 1_000_000 + (((lf_ticks & 0x7FFF) * 1_000_000) >> 15)
+    }
+
+    /// Set compare 0 to a point in the time base started with `start_monotonic`.
+    ///
+    /// Returns `false` if it is too close to the current time to be set or not less than 2^24 ticks
+    /// ahead.
+    pub fn set_alarm(&mut self, ticks: u64) -> bool {
+        self.set_channel_alarm(0, ticks) == Ok(true)
+    }
+
+    /// Set a compare channel to a point in the time base started with `start_monotonic`.
+    ///
+    /// The alarm must be less than 2^24 ticks ahead. Returns `Ok(false)` if it is too close to the
+    /// current time to be set.
+    pub fn set_channel_alarm(&mut self, channel: usize, ticks: u64) -> Result<bool, Error> {
+        if channel >= T::CHANNEL_COUNT {
+            return Err(Error::Channel);
+        }
+
+        // the counter must not reach the compare value while it is written
+        let now = self.ticks();
+
+        if ticks < now + 2 {
+            return Ok(false);
+        }
+
+        if ticks - now > MAX_COMPARE as u64 {
+            return Err(Error::Compare);
+        }
+
+        self.rtc.cc[channel].write(|w| unsafe { w.compare().bits((ticks as u32) & MAX_COMPARE) });
+        self.rtc.events_compare[channel].write(|w| w.events_compare().not_generated());
+        self.rtc
+            .evtenset
+            .write(|w| unsafe { w.bits(INTEN_COMPARE0 << channel) });
+        self.rtc
+            .intenset
+            .write(|w| unsafe { w.bits(INTEN_COMPARE0 << channel) });
+
+        Ok(true)
+    }
+
+    /// Wait for the alarm set with `set_alarm`; the counter keeps running.
+    pub fn wait_alarm(&self) {
+        self.wait_channel_alarm(0);
+    }
+
+    /// Wait for the alarm of a channel; the counter keeps running.
+    pub fn wait_channel_alarm(&self, channel: usize) {
+        loop {
+            // the interrupt handler disables the interrupt of the channel after waking up
+            self.rtc
+                .intenset
+                .write(|w| unsafe { w.bits(INTEN_COMPARE0 << channel) });
+
+            if self.is_alarm(channel) {
+                break;
+            }
+
+            cortex_m::asm::wfi();
+        }
+
+        self.rtc.events_compare[channel].write(|w| w.events_compare().not_generated());
+    }
+
+    /// Check whether the alarm of a channel has expired without waiting for it.
+    pub fn is_alarm(&self, channel: usize) -> bool {
+        self.rtc.events_compare[channel]
+            .read()
+            .events_compare()
+            .is_generated()
+    }
+
+    /// Wait for the alarm set with `set_alarm` without blocking the executor.
+    pub async fn alarm(&self) {
+        executor::poll_fn(|cx| {
+            T::waker().register(cx.waker());
+            // the interrupt handler disables the interrupt after waking the future
+            self.rtc.intenset.write(|w| w.compare0().set());
+
+            match self.is_alarm(0) {
+                true => {
+                    self.rtc.events_compare[0].write(|w| w.events_compare().not_generated());
+
+                    Poll::Ready(())
+                }
+                false => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Wait for the next timer of a queue to expire and return its id.
+    ///
+    /// Compare channel 0 is used for the alarms. Deadlines more than 2^24 ticks ahead are
+    /// approached in steps.
+    pub fn wait_queue(&mut self, queue: &mut TimerQueue) -> Option<usize> {
+        loop {
+            let now = self.ticks();
+
+            if let Some(id) = queue.pop_expired(now) {
+                return Some(id);
+            }
+
+            let deadline = queue.next_deadline()?;
+            let alarm = deadline.min(now + MAX_COMPARE as u64);
+
+            if self.set_alarm(alarm) {
+                self.wait_alarm();
+            }
+        }
+    }
+
+    /// Convert a time in µs into ticks of the current prescaler.
+    pub fn micros_to_ticks(&self, micros: u64) -> u64 {
+        micros_to_ticks::<T>(micros)
+    }
+
     pub fn wait(&self) {
         while self.rtc.events_compare[0]
             .read()
@@ -48,8 +286,216 @@ impl<'a> Rtc<'a> {
     }
 }
 
+/// Timer of a `TimerQueue`
+#[derive(Clone, Copy)]
+struct QueueEntry {
+    id: usize,
+    deadline: u64,
+    period: Option<u64>,
+}
+
+/// Software timers sharing one compare channel
+///
+/// The timers are identified by ids chosen by the application (e.g. constants for measuring,
+/// transmitting and blinking).
+pub struct TimerQueue {
+    entries: [Option<QueueEntry>; QUEUE_LEN],
+}
+
+impl TimerQueue {
+    pub const fn new() -> TimerQueue {
+        TimerQueue {
+            entries: [None; QUEUE_LEN],
+        }
+    }
+
+    /// Schedule a timer; an existing timer with the same id is replaced.
+    ///
+    /// # Arguments
+    ///
+    /// * `deadline` - ticks of the time base when the timer expires
+    /// * `period` - ticks after which a periodic timer expires again
+    ///
+    pub fn schedule(&mut self, id: usize, deadline: u64, period: Option<u64>) -> Result<(), Error> {
+        self.cancel(id);
+
+        let slot = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .ok_or(Error::QueueFull)?;
+
+        *slot = Some(QueueEntry {
+            id,
+            deadline,
+            // a period of 0 would expire forever
+            period: period.map(|period| period.max(1)),
+        });
+
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, id: usize) {
+        for entry in self.entries.iter_mut() {
+            if matches!(entry, Some(timer) if timer.id == id) {
+                *entry = None;
+            }
+        }
+    }
+
+    /// Get the earliest deadline (ticks).
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|entry| entry.deadline)
+            .min()
+    }
+
+    /// Remove the expired timer with the earliest deadline and return its id.
+    ///
+    /// A periodic timer is scheduled again; periods missed entirely are skipped.
+    pub fn pop_expired(&mut self, now: u64) -> Option<usize> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .filter(|entry| matches!(entry, Some(timer) if timer.deadline <= now))
+            .min_by_key(|entry| entry.map(|timer| timer.deadline))?;
+        let timer = entry.take()?;
+
+        if let Some(period) = timer.period {
+            let missed = (now - timer.deadline) / period;
+
+            *entry = Some(QueueEntry {
+                deadline: timer.deadline + (missed + 1) * period,
+                ..timer
+            });
+        }
+
+        Some(timer.id)
+    }
+}
+
+impl Default for TimerQueue {
+    fn default() -> TimerQueue {
+        TimerQueue::new()
+    }
+}
+
+/// Monotonic clock for RTIC based on the time base of an RTC
+///
+/// The instants are ticks of the RTC. RTIC starts the clock through `reset`, which also unmasks the
+/// interrupt counting the overflows; the prescaler must be set before. With `MonotonicRtc1`, the
+/// application binds `RTC1` and calls `on_interrupt::<pac::RTC1>()` from the handler.
+pub struct Monotonic<T: Instance> {
+    _rtc: PhantomData<T>,
+}
+
+pub type MonotonicRtc0 = Monotonic<pac::RTC0>;
+pub type MonotonicRtc1 = Monotonic<pac::RTC1>;
+
+impl<T: Instance> Monotonic<T> {
+    /// Convert a time in µs into ticks of the current prescaler.
+    pub fn micros_to_ticks(micros: u64) -> u64 {
+        micros_to_ticks::<T>(micros)
+    }
+}
+
+impl<T: Instance> rtic::Monotonic for Monotonic<T> {
+    type Instant = u64;
+
+    fn ratio() -> rtic::Fraction {
+        // SysTick runs at 64 MHz; 64 MHz / 32768 Hz = 15625 / 8
+        let prescaler = T::registers().prescaler.read().prescaler().bits() as u32;
+
+        rtic::Fraction {
+            numerator: 15625 * (prescaler + 1),
+            denominator: 8,
+        }
+    }
+
+    fn now() -> u64 {
+        ticks::<T>()
+    }
+
+    unsafe fn reset() {
+        reset::<T>();
+        // RTIC does not enable the interrupt of the monotonic
+        cortex_m::peripheral::NVIC::unmask(T::INTERRUPT);
+    }
+
+    fn zero() -> u64 {
+        0
+    }
+}
+
+/// Clear the counter and count the overflows from now on.
+unsafe fn reset<T: Instance>() {
+    let rtc = T::registers();
+
+    T::overflows().store(0, Ordering::SeqCst);
+    rtc.events_ovrflw
+        .write(|w| w.events_ovrflw().not_generated());
+    rtc.intenset.write(|w| w.bits(INTEN_OVRFLW));
+    rtc.tasks_clear.write(|w| w.tasks_clear().trigger());
+    rtc.tasks_start.write(|w| w.tasks_start().trigger());
+}
+
+fn ticks<T: Instance>() -> u64 {
+    let rtc = T::registers();
+    let overflows_counted = T::overflows();
+
+    loop {
+        let overflows = overflows_counted.load(Ordering::SeqCst);
+        let counter = rtc.counter.read().counter().bits();
+        let pending = rtc.events_ovrflw.read().events_ovrflw().is_generated();
+
+        if overflows != overflows_counted.load(Ordering::SeqCst) {
+            // the interrupt handler ran in between; try again
+            continue;
+        }
+
+        // an overflow that has not been handled yet only counts if the counter was read after it
+        let overflows = match pending && counter < 0x80_0000 {
+            true => overflows as u64 + 1,
+            false => overflows as u64,
+        };
+
+        return (overflows << 24) + counter as u64;
+    }
+}
+
+fn micros_to_ticks<T: Instance>(micros: u64) -> u64 {
+    let prescaler = T::registers().prescaler.read().prescaler().bits() as u64;
+
+    ((micros << 15) / 1_000_000) / (prescaler + 1)
+}
+
+/// Count overflows, disable the interrupts of expired alarms and wake the waiting future.
+pub fn on_interrupt<T: Instance>() {
+    let rtc = T::registers();
+
+    if rtc.events_ovrflw.read().events_ovrflw().is_generated() {
+        rtc.events_ovrflw
+            .write(|w| w.events_ovrflw().not_generated());
+        T::overflows().fetch_add(1, Ordering::SeqCst);
+    }
+
+    for channel in 0..T::CHANNEL_COUNT {
+        if rtc.events_compare[channel]
+            .read()
+            .events_compare()
+            .is_generated()
+        {
+            rtc.intenclr
+                .write(|w| unsafe { w.bits(INTEN_COMPARE0 << channel) });
+        }
+    }
+
+    T::waker().wake();
+}
+
 #[interrupt]
 fn RTC0() {
-    let device = unsafe { pac::Peripherals::steal() };
-    device.RTC0.intenclr.write(|w| w.compare0().clear());
+    on_interrupt::<pac::RTC0>();
 }
@@ -1,16 +1,32 @@
+use super::p0;
 use nrf52810_pac as pac;
 
 pub struct Saadc {
     saadc: pac::SAADC,
-    p0: pac::P0,
-    pin: usize,
+    pin: p0::Pin<p0::Input>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// the pin is not an analog input (P0.02-P0.05, P0.28-P0.31)
+    NotAnalogInput,
 }
 
 impl Saadc {
-    pub fn new(saadc: pac::SAADC, p0: pac::P0, pin: usize) -> Self {
-        // select P0.03/AIN1 as the positive input
-        p0.pin_cnf[pin].write(|w| w.input().connect());
-        saadc.ch[0].pselp.write(|w| w.pselp().analog_input1());
+    pub fn new<MODE>(saadc: pac::SAADC, pin: p0::Pin<MODE>) -> Result<Self, Error> {
+        // select the analog input of the pin as the positive input
+        match pin.pin() {
+            2 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input0()),
+            3 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input1()),
+            4 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input2()),
+            5 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input3()),
+            28 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input4()),
+            29 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input5()),
+            30 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input6()),
+            31 => saadc.ch[0].pselp.write(|w| w.pselp().analog_input7()),
+            _ => return Err(Error::NotAnalogInput),
+        }
+        let pin = pin.into_input(p0::Pull::Disabled);
         // set gain 1
         saadc.ch[0].config.write(|w| w.gain().gain1());
         // set max count 1
@@ -18,7 +34,7 @@ impl Saadc {
         // enable
         saadc.enable.write(|w| w.enable().enabled());
 
-        Saadc { saadc, p0, pin }
+        Ok(Saadc { saadc, pin })
     }
 
     pub fn getValue(&mut self) -> f32 {
@@ -58,12 +74,10 @@ impl Saadc {
         res
     }
 
-    pub fn free(self) -> (pac::SAADC, pac::P0) {
+    pub fn free(self) -> (pac::SAADC, p0::Pin<p0::Disconnected>) {
         // disable SAADC
         self.saadc.enable.write(|w| w.enable().disabled());
-        // disconnect pin
-        self.p0.pin_cnf[self.pin].write(|w| w.input().disconnect());
 
-        (self.saadc, self.p0)
+        (self.saadc, self.pin.into_disconnected())
     }
 }
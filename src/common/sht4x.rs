@@ -1,16 +1,16 @@
-use super::timer;
-use super::twim;
+use embedded_hal::blocking::{delay, i2c};
 
 #[derive(core::fmt::Debug)]
-pub enum Sht4xError {
+pub enum Sht4xError<E> {
+    EmbeddedError(E),
     IdError,
     CrcError,
     TimerError,
 }
 
-pub struct SHT4X<'a> {
-    twim: &'a mut twim::Twim<'a>,
-    timer: &'a mut timer::Timer<'a>,
+pub struct SHT4X<'a, I2C, DELAY> {
+    i2c: &'a mut I2C,
+    delay: &'a mut DELAY,
     address: u8,
     buffer: [u8; 1],
 }
@@ -20,50 +20,44 @@ pub struct Measurement {
     pub humidity: f32,
 }
 
-impl<'a> SHT4X<'a> {
-    pub fn new(
-        twim: &'a mut twim::Twim<'a>,
-        timer: &'a mut timer::Timer<'a>,
-        address: u8,
-    ) -> SHT4X<'a> {
+impl<'a, I2C, DELAY, E> SHT4X<'a, I2C, DELAY>
+where
+    I2C: i2c::Write<Error = E> + i2c::Read<Error = E>,
+    DELAY: delay::DelayUs<u32>,
+{
+    pub fn new(i2c: &'a mut I2C, delay: &'a mut DELAY, address: u8) -> SHT4X<'a, I2C, DELAY> {
         SHT4X {
-            twim,
-            timer,
+            i2c,
+            delay,
             address: address,
             buffer: [0; 1],
         }
     }
 
-    pub fn start_reading_serial(&mut self) -> Result<(), Sht4xError> {
+    pub fn start_reading_serial(&mut self) -> Result<(), Sht4xError<E>> {
         self.buffer[0] = 0x89;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
-        // TODO: check that timer is not running already
-        self.timer.set_timeout_mus(1_000);
-        self.timer.start();
+        self.i2c
+            .write(self.address, &self.buffer)
+            .map_err(Sht4xError::EmbeddedError)?;
 
         Ok(())
     }
 
-    pub fn start_measurement(&mut self) -> Result<(), Sht4xError> {
+    pub fn start_measurement(&mut self) -> Result<(), Sht4xError<E>> {
         self.buffer[0] = 0xFD;
-        self.twim.start_write(self.address, &self.buffer);
-        self.twim.wait().unwrap();
-
-        // TODO: check that the timer is not running already
-        self.timer.set_timeout_mus(10_000);
-        self.timer.start();
+        self.i2c
+            .write(self.address, &self.buffer)
+            .map_err(Sht4xError::EmbeddedError)?;
 
         Ok(())
     }
 
-    pub fn wait_for_serial(&mut self) -> Result<u32, Sht4xError> {
-        // check that timer is active
-        self.timer.wait();
+    pub fn wait_for_serial(&mut self) -> Result<u32, Sht4xError<E>> {
+        self.delay.delay_us(1_000);
         let mut buffer: [u8; 6] = [0; 6];
-        self.twim.start_read(self.address, &mut buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .read(self.address, &mut buffer)
+            .map_err(Sht4xError::EmbeddedError)?;
 
         if buffer[2] != crc8(&buffer[0..2]) || buffer[5] != crc8(&buffer[3..5]) {
             return Err(Sht4xError::CrcError);
@@ -74,12 +68,13 @@ impl<'a> SHT4X<'a> {
         ]))
     }
 
-    pub fn wait_for_measurement(&mut self) -> Result<Measurement, Sht4xError> {
-        // check that timer is active
-        self.timer.wait();
+    pub fn wait_for_measurement(&mut self) -> Result<Measurement, Sht4xError<E>> {
+        // high repeatability measurements take up to 8.3 ms
+        self.delay.delay_us(10_000);
         let mut buffer: [u8; 6] = [0; 6];
-        self.twim.start_read(self.address, &mut buffer);
-        self.twim.wait().unwrap();
+        self.i2c
+            .read(self.address, &mut buffer)
+            .map_err(Sht4xError::EmbeddedError)?;
 
         if buffer[2] != crc8(&buffer[0..2]) || buffer[5] != crc8(&buffer[3..5]) {
             return Err(Sht4xError::CrcError);
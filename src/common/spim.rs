@@ -0,0 +1,314 @@
+use super::p0;
+use embedded_hal::blocking::spi;
+use nrf52810_pac as pac;
+use pac::interrupt;
+
+/// Longest EasyDMA transfer; longer transfers are split
+const MAX_TRANSFER_LEN: usize = 255;
+/// Start of the RAM; EasyDMA cannot read data below (in flash)
+const RAM_START: u32 = 0x2000_0000;
+/// Data outside the RAM is copied to the stack in chunks of this length
+const COPY_BUFFER_LEN: usize = 32;
+/// Byte sent while reading beyond the data to write
+const OVER_READ_CHARACTER: u8 = 0xFF;
+
+pub struct Spim<'a> {
+    spim: &'a mut pac::SPIM0,
+    // borrowed while the peripheral uses them
+    _sck: &'a mut p0::Pin<p0::Output>,
+    _mosi: &'a mut p0::Pin<p0::Output>,
+    _miso: &'a mut p0::Pin<p0::Input>,
+    cs: &'a mut p0::Pin<p0::Output>,
+    /// the chip select was asserted with `select` and is kept over transfers
+    held: bool,
+}
+
+pub enum Frequency {
+    K125,
+    K250,
+    K500,
+    M1,
+    M2,
+    M4,
+    M8,
+}
+
+/// Clock polarity and phase
+pub enum Mode {
+    /// clock idle low, data sampled on the rising edge
+    Mode0,
+    /// clock idle low, data sampled on the falling edge
+    Mode1,
+    /// clock idle high, data sampled on the falling edge
+    Mode2,
+    /// clock idle high, data sampled on the rising edge
+    Mode3,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// fewer bytes than expected were sent
+    Transmit,
+    /// fewer bytes than expected were received
+    Receive,
+}
+
+impl<'a> Spim<'a> {
+    /// Create a new SPI master.
+    ///
+    /// The clock, data out and chip select pins are expected to be outputs (see
+    /// `p0::Pin::into_output`), data in an input; the clock is set to its idle level and the chip
+    /// select is released.
+    ///
+    /// # Arguments
+    ///
+    /// * `cs` - chip select pin (active low); it is asserted for each transfer
+    ///
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        spim: &'a mut pac::SPIM0,
+        nvic: &mut pac::NVIC,
+        sck: &'a mut p0::Pin<p0::Output>,
+        mosi: &'a mut p0::Pin<p0::Output>,
+        miso: &'a mut p0::Pin<p0::Input>,
+        cs: &'a mut p0::Pin<p0::Output>,
+        freq: Frequency,
+        mode: Mode,
+    ) -> Spim<'a> {
+        // set idle levels; the input buffer of the clock is connected as recommended
+        match mode {
+            Mode::Mode0 | Mode::Mode1 => sck.set_low(),
+            Mode::Mode2 | Mode::Mode3 => sck.set_high(),
+        }
+        sck.connect_input();
+        mosi.set_low();
+        cs.set_high();
+
+        #[allow(deprecated)]
+        nvic.enable(pac::interrupt::SPIM0_SPIS0_SPI0);
+        spim.psel
+            .sck
+            .write(|w| unsafe { w.pin().bits(sck.pin() as u8).connect().connected() });
+        spim.psel
+            .mosi
+            .write(|w| unsafe { w.pin().bits(mosi.pin() as u8).connect().connected() });
+        spim.psel
+            .miso
+            .write(|w| unsafe { w.pin().bits(miso.pin() as u8).connect().connected() });
+
+        match freq {
+            Frequency::K125 => spim.frequency.write(|w| w.frequency().k125()),
+            Frequency::K250 => spim.frequency.write(|w| w.frequency().k250()),
+            Frequency::K500 => spim.frequency.write(|w| w.frequency().k500()),
+            Frequency::M1 => spim.frequency.write(|w| w.frequency().m1()),
+            Frequency::M2 => spim.frequency.write(|w| w.frequency().m2()),
+            Frequency::M4 => spim.frequency.write(|w| w.frequency().m4()),
+            Frequency::M8 => spim.frequency.write(|w| w.frequency().m8()),
+        }
+
+        match mode {
+            Mode::Mode0 => spim
+                .config
+                .write(|w| w.order().msb_first().cpol().active_high().cpha().leading()),
+            Mode::Mode1 => spim
+                .config
+                .write(|w| w.order().msb_first().cpol().active_high().cpha().trailing()),
+            Mode::Mode2 => spim
+                .config
+                .write(|w| w.order().msb_first().cpol().active_low().cpha().leading()),
+            Mode::Mode3 => spim
+                .config
+                .write(|w| w.order().msb_first().cpol().active_low().cpha().trailing()),
+        }
+
+        spim.orc
+            .write(|w| unsafe { w.orc().bits(OVER_READ_CHARACTER) });
+
+        Spim {
+            spim,
+            _sck: sck,
+            _mosi: mosi,
+            _miso: miso,
+            cs,
+            held: false,
+        }
+    }
+
+    /// Start a transfer of at most `MAX_TRANSFER_LEN` bytes.
+    ///
+    /// The bytes received while writing are stored in `rx_buffer`; if it is longer than `tx_buffer`,
+    /// `OVER_READ_CHARACTER` is sent for the remaining bytes. This function is not safe in the
+    /// sense that the buffers could be changed or dropped before `wait` has finished.
+    pub fn start_transfer(&mut self, tx_buffer: &[u8], rx_buffer: &mut [u8]) {
+        self.start(
+            tx_buffer.as_ptr() as u32,
+            tx_buffer.len(),
+            rx_buffer.as_mut_ptr() as u32,
+            rx_buffer.len(),
+        );
+    }
+
+    fn start(&mut self, tx_ptr: u32, tx_len: usize, rx_ptr: u32, rx_len: usize) {
+        // set buffers
+        self.spim.txd.ptr.write(|w| unsafe { w.ptr().bits(tx_ptr) });
+        self.spim
+            .txd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(tx_len as _) });
+        self.spim.rxd.ptr.write(|w| unsafe { w.ptr().bits(rx_ptr) });
+        self.spim
+            .rxd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(rx_len as _) });
+
+        // set interrupts
+        self.spim.intenset.write(|w| w.end().set());
+
+        // enable
+        self.spim.enable.write(|w| w.enable().enabled());
+
+        // trigger transfer
+        self.spim
+            .events_end
+            .write(|w| w.events_end().not_generated());
+        self.spim.tasks_start.write(|w| w.tasks_start().trigger());
+    }
+
+    /// Wait for the transfer to complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx_len` - number of bytes to send
+    /// * `rx_len` - number of bytes to receive
+    ///
+    pub fn wait(&mut self, tx_len: usize, rx_len: usize) -> Result<(), Error> {
+        while self.spim.events_end.read().events_end().is_not_generated() {
+            cortex_m::asm::wfi();
+        }
+
+        self.spim
+            .events_end
+            .write(|w| w.events_end().not_generated());
+        self.spim.enable.write(|w| w.enable().disabled());
+
+        if (self.spim.txd.amount.read().amount().bits() as usize) < tx_len {
+            return Err(Error::Transmit);
+        }
+
+        if (self.spim.rxd.amount.read().amount().bits() as usize) < rx_len {
+            return Err(Error::Receive);
+        }
+
+        Ok(())
+    }
+
+    /// Assert the chip select until `deselect`.
+    ///
+    /// Transfers assert the chip select themselves and release it afterwards; use this to keep the
+    /// device selected over several transfers (e.g. a command followed by data).
+    pub fn select(&mut self) {
+        self.held = true;
+        self.cs.set_low();
+    }
+
+    /// Release the chip select.
+    pub fn deselect(&mut self) {
+        self.held = false;
+        self.cs.set_high();
+    }
+
+    /// Write and read at the same time with the chip select asserted.
+    pub fn transfer(&mut self, tx_buffer: &[u8], rx_buffer: &mut [u8]) -> Result<(), Error> {
+        self.with_cs(|spim| spim.exchange(tx_buffer, rx_buffer))
+    }
+
+    /// Write and then read with the chip select asserted, e.g. a command and its response.
+    pub fn write_then_read(&mut self, tx_buffer: &[u8], rx_buffer: &mut [u8]) -> Result<(), Error> {
+        self.with_cs(|spim| {
+            spim.exchange(tx_buffer, &mut [])
+                .and_then(|_| spim.exchange(&[], rx_buffer))
+        })
+    }
+
+    /// Run transfers with the chip select asserted; it is released afterwards unless `select`
+    /// asserted it.
+    fn with_cs<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        if self.held {
+            return f(self);
+        }
+
+        self.cs.set_low();
+        let result = f(self);
+        self.cs.set_high();
+
+        result
+    }
+
+    /// Transfer buffers of any length and location in chunks EasyDMA can handle.
+    fn exchange(&mut self, tx_buffer: &[u8], rx_buffer: &mut [u8]) -> Result<(), Error> {
+        let in_ram = tx_buffer.is_empty() || tx_buffer.as_ptr() as u32 >= RAM_START;
+        let chunk_len = match in_ram {
+            true => MAX_TRANSFER_LEN,
+            false => COPY_BUFFER_LEN,
+        };
+        let len = tx_buffer.len().max(rx_buffer.len());
+        let mut position = 0;
+
+        while position < len {
+            let end = (position + chunk_len).min(len);
+            let tx = &tx_buffer[position.min(tx_buffer.len())..end.min(tx_buffer.len())];
+            let rx_len = rx_buffer.len();
+            let rx = &mut rx_buffer[position.min(rx_len)..end.min(rx_len)];
+            let mut copy = [0u8; COPY_BUFFER_LEN];
+            let tx = match in_ram {
+                true => tx,
+                false => {
+                    copy[..tx.len()].copy_from_slice(tx);
+                    &copy[..tx.len()]
+                }
+            };
+            let (tx_len, rx_len) = (tx.len(), rx.len());
+
+            self.start_transfer(tx, rx);
+            self.wait(tx_len, rx_len)?;
+            position = end;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> spi::Transfer<u8> for Spim<'a> {
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Error> {
+        // EasyDMA sends each byte before the received one is stored in its place
+        let result: Result<(), Error> = self.with_cs(|spim| {
+            for chunk in words.chunks_mut(MAX_TRANSFER_LEN) {
+                let len = chunk.len();
+                let ptr = chunk.as_mut_ptr() as u32;
+
+                spim.start(ptr, len, ptr, len);
+                spim.wait(len, len)?;
+            }
+
+            Ok(())
+        });
+
+        result.map(move |_| &*words)
+    }
+}
+
+impl<'a> spi::Write<u8> for Spim<'a> {
+    type Error = Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Error> {
+        Spim::transfer(self, words, &mut [])
+    }
+}
+
+#[interrupt]
+fn SPIM0_SPIS0_SPI0() {
+    let device = unsafe { pac::Peripherals::steal() };
+    device.SPIM0.intenclr.write(|w| w.end().clear());
+}
@@ -0,0 +1,121 @@
+//! Synchronisation of the local clock of a node with the time beacons of the gateway
+//!
+//! All times are in µs. The local time is the monotonic time of the node, the network time the
+//! time carried by the beacons.
+
+/// Largest drift of the local clock that is accepted (ppm)
+const MAX_DRIFT_PPM: i64 = 500;
+/// Uncertainty of a beacon's reception time (µs)
+const BEACON_JITTER_MUS: u64 = 2_000;
+/// Drift assumed for the uncertainty of the predicted time (ppm)
+const UNCERTAINTY_PPM: u64 = 100;
+
+pub struct TimeSync {
+    /// local and network time of the last beacon
+    reference: Option<(u64, u64)>,
+    /// drift of the local clock against the network clock (ppm; positive if the local clock is fast)
+    drift_ppm: i64,
+    /// time between two beacons
+    interval: u64,
+    absolute: bool,
+}
+
+impl TimeSync {
+    pub fn new() -> TimeSync {
+        TimeSync {
+            reference: None,
+            drift_ppm: 0,
+            interval: 0,
+            absolute: false,
+        }
+    }
+
+    pub fn is_synchronised(&self) -> bool {
+        self.reference.is_some()
+    }
+
+    /// The network time has been set by the host (µs since the Unix epoch).
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    /// Discipline the local clock with a received beacon.
+    ///
+    /// # Arguments
+    ///
+    /// * `local` - local time of the reception of the beacon
+    /// * `network` - network time carried by the beacon
+    /// * `interval` - time between two beacons
+    /// * `absolute` - the network time is the time since the Unix epoch
+    ///
+    pub fn on_beacon(&mut self, local: u64, network: u64, interval: u64, absolute: bool) {
+        if let (Some((ref_local, ref_network)), true) = (self.reference, absolute == self.absolute)
+        {
+            let elapsed_local = local.wrapping_sub(ref_local) as i64;
+            let elapsed_network = network.wrapping_sub(ref_network) as i64;
+
+            // the drift can only be estimated reliably over longer periods
+            if elapsed_network > 1_000_000 {
+                let drift_ppm = (elapsed_local - elapsed_network) * 1_000_000 / elapsed_network;
+
+                if drift_ppm.abs() <= MAX_DRIFT_PPM {
+                    self.drift_ppm = drift_ppm;
+                }
+            }
+        } else {
+            self.drift_ppm = 0;
+        }
+
+        self.reference = Some((local, network));
+        self.interval = interval;
+        self.absolute = absolute;
+    }
+
+    /// Convert a local time into network time.
+    pub fn network_time(&self, local: u64) -> Option<u64> {
+        let (ref_local, ref_network) = self.reference?;
+        let elapsed = local.wrapping_sub(ref_local) as i64;
+
+        Some(ref_network.wrapping_add((elapsed - elapsed * self.drift_ppm / 1_000_000) as u64))
+    }
+
+    /// Convert a network time into local time.
+    pub fn local_time(&self, network: u64) -> Option<u64> {
+        let (ref_local, ref_network) = self.reference?;
+        let elapsed = network.wrapping_sub(ref_network) as i64;
+
+        Some(ref_local.wrapping_add((elapsed + elapsed * self.drift_ppm / 1_000_000) as u64))
+    }
+
+    /// Get the local time of the next beacon after `local`.
+    pub fn next_beacon(&self, local: u64) -> Option<u64> {
+        let (_, ref_network) = self.reference?;
+
+        if self.interval == 0 {
+            return None;
+        }
+
+        let elapsed = self.network_time(local)?.saturating_sub(ref_network);
+        let next = ref_network + (elapsed / self.interval + 1) * self.interval;
+
+        self.local_time(next)
+    }
+
+    /// Get the uncertainty of a local time predicted for `local`.
+    ///
+    /// A beacon should be listened for from the predicted time minus to plus the uncertainty.
+    pub fn uncertainty(&self, local: u64) -> u64 {
+        match self.reference {
+            Some((ref_local, _)) => {
+                BEACON_JITTER_MUS + local.saturating_sub(ref_local) * UNCERTAINTY_PPM / 1_000_000
+            }
+            None => u64::MAX,
+        }
+    }
+}
+
+impl Default for TimeSync {
+    fn default() -> TimeSync {
+        TimeSync::new()
+    }
+}
@@ -1,21 +1,146 @@
+use super::executor;
+use core::task::Poll;
+use embedded_hal::blocking::delay;
+use embedded_hal::timer;
 use nrf52810_pac as pac;
 use pac::interrupt;
 
-pub struct Timer<'a> {
-    timer: &'a mut pac::TIMER0,
+/// Number of compare/capture channels
+pub const CHANNEL_COUNT: usize = 4;
+/// Prescaler for a 1 MHz tick (16 MHz / 2^4)
+pub const DEFAULT_PRESCALER: u8 = 4;
+/// Largest prescaler (31.25 kHz)
+const MAX_PRESCALER: u8 = 9;
+/// Frequency of the timer without prescaler (Hz)
+const BASE_FREQUENCY: u32 = 16_000_000;
+
+/// Wakers of the delays in progress (one per instance)
+static WAKER0: executor::WakerSlot = executor::WakerSlot::new();
+static WAKER1: executor::WakerSlot = executor::WakerSlot::new();
+static WAKER2: executor::WakerSlot = executor::WakerSlot::new();
+
+/// Timer peripheral (TIMER0, TIMER1 or TIMER2)
+pub trait Instance: core::ops::Deref<Target = pac::timer0::RegisterBlock> {
+    const INTERRUPT: pac::Interrupt;
+
+    fn registers() -> &'static pac::timer0::RegisterBlock;
+    fn waker() -> &'static executor::WakerSlot;
+}
+
+macro_rules! impl_instance {
+    ($timer:ident, $waker:ident) => {
+        impl Instance for pac::$timer {
+            const INTERRUPT: pac::Interrupt = pac::Interrupt::$timer;
+
+            fn registers() -> &'static pac::timer0::RegisterBlock {
+                unsafe { &*pac::$timer::ptr() }
+            }
+
+            fn waker() -> &'static executor::WakerSlot {
+                &$waker
+            }
+        }
+    };
+}
+
+impl_instance!(TIMER0, WAKER0);
+impl_instance!(TIMER1, WAKER1);
+impl_instance!(TIMER2, WAKER2);
+
+/// Behaviour of the timer when the counter reaches a compare value
+pub enum Mode {
+    /// the timer stops
+    OneShot,
+    /// the counter is cleared and keeps running
+    Periodic,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// the channel does not exist
+    Channel,
+    /// the prescaler is larger than 9
+    Prescaler,
+}
+
+pub struct Timer<'a, T: Instance = pac::TIMER0> {
+    timer: &'a mut T,
+    prescaler: u8,
 }
 
-impl<'a> Timer<'a> {
-    pub fn new(timer: &'a mut pac::TIMER0, nvic: &mut pac::NVIC) -> Self {
+impl<'a, T: Instance> Timer<'a, T> {
+    /// Create a timer counting at 1 MHz with a 32 bit counter.
+    pub fn new(timer: &'a mut T, nvic: &mut pac::NVIC) -> Self {
         #[allow(deprecated)]
-        nvic.enable(pac::interrupt::TIMER0);
+        nvic.enable(T::INTERRUPT);
+        timer.mode.write(|w| w.mode().timer());
         timer.bitmode.write(|w| w.bitmode()._32bit());
+        timer
+            .prescaler
+            .write(|w| unsafe { w.prescaler().bits(DEFAULT_PRESCALER) });
 
-        Timer { timer }
+        Timer {
+            timer,
+            prescaler: DEFAULT_PRESCALER,
+        }
+    }
+
+    /// Set the prescaler; the timer counts at 16 MHz / 2^prescaler.
+    ///
+    /// The prescaler must only be changed while the timer is stopped.
+    pub fn set_prescaler(&mut self, prescaler: u8) -> Result<(), Error> {
+        if prescaler > MAX_PRESCALER {
+            return Err(Error::Prescaler);
+        }
+
+        self.timer
+            .prescaler
+            .write(|w| unsafe { w.prescaler().bits(prescaler) });
+        self.prescaler = prescaler;
+
+        Ok(())
+    }
+
+    /// Frequency the timer counts at (Hz)
+    pub fn frequency(&self) -> u32 {
+        BASE_FREQUENCY >> self.prescaler
     }
 
+    /// Convert a time (µs) to ticks of the timer; the result saturates at the 32 bit range.
+    pub fn micros_to_ticks(&self, micros: u32) -> u32 {
+        let ticks = (micros as u64 * (BASE_FREQUENCY / 1_000_000) as u64) >> self.prescaler;
+
+        ticks.min(u32::MAX as u64) as u32
+    }
+
+    /// Set a compare value (ticks) of a channel.
+    ///
+    /// The interrupt of the channel is enabled by `start`; `wait_compare` and `is_compare` tell
+    /// whether the counter has reached the value.
+    pub fn set_compare(&mut self, channel: usize, ticks: u32, mode: Mode) -> Result<(), Error> {
+        check_channel(channel)?;
+
+        // SHORTS: COMPAREn_CLEAR at bit n, COMPAREn_STOP at bit n + 8
+        let clear = 1 << channel;
+        let stop = 1 << (channel + 8);
+
+        self.timer.cc[channel].write(|w| unsafe { w.cc().bits(ticks) });
+        self.timer.shorts.modify(|r, w| unsafe {
+            w.bits(match mode {
+                Mode::OneShot => (r.bits() & !clear) | stop,
+                Mode::Periodic => (r.bits() & !stop) | clear,
+            })
+        });
+        self.timer.events_compare[channel].write(|w| w.events_compare().not_generated());
+
+        Ok(())
+    }
+
+    /// Set a one-shot timeout (µs) on channel 0.
     pub fn set_timeout_mus(&mut self, timeout: u32) {
-        self.timer.cc[0].write(|w| unsafe { w.cc().bits(timeout) });
+        let ticks = self.micros_to_ticks(timeout);
+
+        self.set_compare(0, ticks, Mode::OneShot).unwrap();
     }
 
     pub fn start(&mut self) {
@@ -24,22 +149,224 @@ impl<'a> Timer<'a> {
         self.timer.tasks_start.write(|w| w.tasks_start().trigger());
     }
 
-    pub fn wait(&mut self) {
-        while self.timer.events_compare[0]
+    /// Check whether the timeout has expired without waiting for it.
+    pub fn is_expired(&self) -> bool {
+        self.compare_event(0)
+    }
+
+    /// Check whether the counter has reached the compare value of a channel.
+    pub fn is_compare(&self, channel: usize) -> Result<bool, Error> {
+        check_channel(channel)?;
+
+        Ok(self.compare_event(channel))
+    }
+
+    fn compare_event(&self, channel: usize) -> bool {
+        self.timer.events_compare[channel]
             .read()
             .events_compare()
-            .is_not_generated()
-        {
-            cortex_m::asm::wfi();
+            .is_generated()
+    }
+
+    /// Stop the timer and clear the timeouts.
+    pub fn stop(&mut self) {
+        self.timer.tasks_stop.write(|w| w.tasks_stop().trigger());
+
+        for channel in 0..CHANNEL_COUNT {
+            self.timer.events_compare[channel].write(|w| w.events_compare().not_generated());
         }
+    }
+
+    /// Start the timer without a timeout.
+    ///
+    /// The counter wraps around after 2^32 ticks (µs with the default prescaler); use `capture`
+    /// to read it.
+    pub fn start_free_running(&mut self) {
+        self.timer.shorts.reset();
+        self.timer.tasks_clear.write(|w| w.tasks_clear().trigger());
+        self.timer.tasks_start.write(|w| w.tasks_start().trigger());
+    }
+
+    /// Capture the current counter value into a compare register and return it.
+    pub fn capture(&mut self, channel: usize) -> Result<u32, Error> {
+        check_channel(channel)?;
+        self.timer.tasks_capture[channel].write(|w| w.tasks_capture().trigger());
+
+        self.captured(channel)
+    }
+
+    /// Get the value of a compare register (e.g. captured through PPI).
+    pub fn captured(&self, channel: usize) -> Result<u32, Error> {
+        check_channel(channel)?;
+
+        Ok(self.timer.cc[channel].read().cc().bits())
+    }
+
+    /// Address of a capture task for connecting it through PPI.
+    pub fn task_capture_endpoint(&self, channel: usize) -> Result<u32, Error> {
+        check_channel(channel)?;
+
+        Ok(&self.timer.tasks_capture[channel] as *const _ as u32)
+    }
+
+    /// Address of the start task for connecting it through PPI.
+    pub fn task_start_endpoint(&self) -> u32 {
+        &self.timer.tasks_start as *const _ as u32
+    }
+
+    /// Address of the stop task for connecting it through PPI.
+    pub fn task_stop_endpoint(&self) -> u32 {
+        &self.timer.tasks_stop as *const _ as u32
+    }
+
+    /// Address of the clear task for connecting it through PPI.
+    pub fn task_clear_endpoint(&self) -> u32 {
+        &self.timer.tasks_clear as *const _ as u32
+    }
+
+    /// Address of a compare event for connecting it through PPI.
+    pub fn event_compare_endpoint(&self, channel: usize) -> Result<u32, Error> {
+        check_channel(channel)?;
+
+        Ok(&self.timer.events_compare[channel] as *const _ as u32)
+    }
 
+    pub fn wait(&mut self) {
+        self.wait_compare(0).unwrap();
         self.timer.tasks_stop.write(|w| w.tasks_stop().trigger());
-        self.timer.events_compare[0].write(|w| w.events_compare().not_generated());
+    }
+
+    /// Wait until the counter reaches the compare value of a channel and clear the event.
+    ///
+    /// In periodic mode the timer keeps running, so that this can be called again for the next
+    /// period.
+    pub fn wait_compare(&mut self, channel: usize) -> Result<(), Error> {
+        check_channel(channel)?;
+
+        loop {
+            // the interrupt handler disables the interrupt of the channel after waking up
+            self.timer
+                .intenset
+                .write(|w| unsafe { w.bits(1 << (16 + channel)) });
+
+            if self.compare_event(channel) {
+                break;
+            }
+
+            cortex_m::asm::wfi();
+        }
+
+        self.timer.events_compare[channel].write(|w| w.events_compare().not_generated());
+
+        Ok(())
+    }
+
+    /// Wait for a time (µs) without blocking the executor.
+    pub async fn delay_async(&mut self, timeout: u32) {
+        // a compare value of 0 would only match after the counter wrapped around
+        self.set_timeout_mus(timeout.max(1));
+        self.start();
+
+        executor::poll_fn(|cx| {
+            T::waker().register(cx.waker());
+            // the interrupt handler disables the interrupt after waking the future
+            self.timer.intenset.write(|w| w.compare0().set());
+
+            match self.is_expired() {
+                true => {
+                    self.stop();
+
+                    Poll::Ready(())
+                }
+                false => Poll::Pending,
+            }
+        })
+        .await
     }
 }
 
+impl<'a, T: Instance> delay::DelayUs<u32> for Timer<'a, T> {
+    fn delay_us(&mut self, us: u32) {
+        // a compare value of 0 would only match after the counter wrapped around
+        self.set_timeout_mus(us.max(1));
+        self.start();
+        self.wait();
+    }
+}
+
+impl<'a, T: Instance> delay::DelayMs<u32> for Timer<'a, T> {
+    fn delay_ms(&mut self, ms: u32) {
+        delay::DelayUs::delay_us(self, ms.saturating_mul(1_000));
+    }
+}
+
+impl<'a, T: Instance> delay::DelayMs<u8> for Timer<'a, T> {
+    fn delay_ms(&mut self, ms: u8) {
+        delay::DelayMs::delay_ms(self, ms as u32);
+    }
+}
+
+/// Count down on channel 0; the time is given in µs.
+impl<'a, T: Instance> timer::CountDown for Timer<'a, T> {
+    type Time = u32;
+
+    fn start<C: Into<u32>>(&mut self, count: C) {
+        self.stop();
+        // a compare value of 0 would only match after the counter wrapped around
+        self.set_timeout_mus(count.into().max(1));
+        Timer::start(self);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), void::Void> {
+        match self.is_expired() {
+            true => {
+                self.timer.events_compare[0].write(|w| w.events_compare().not_generated());
+
+                Ok(())
+            }
+            false => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+fn check_channel(channel: usize) -> Result<(), Error> {
+    if channel >= CHANNEL_COUNT {
+        return Err(Error::Channel);
+    }
+
+    Ok(())
+}
+
+/// Disable the interrupts of the channels with an event and wake the waiting future.
+fn on_interrupt<T: Instance>() {
+    let timer = T::registers();
+
+    for channel in 0..CHANNEL_COUNT {
+        if timer.events_compare[channel]
+            .read()
+            .events_compare()
+            .is_generated()
+        {
+            timer
+                .intenclr
+                .write(|w| unsafe { w.bits(1 << (16 + channel)) });
+        }
+    }
+
+    T::waker().wake();
+}
+
 #[interrupt]
 fn TIMER0() {
-    let device = unsafe { pac::Peripherals::steal() };
-    device.TIMER0.intenclr.write(|w| w.compare0().clear());
+    on_interrupt::<pac::TIMER0>();
+}
+
+#[interrupt]
+fn TIMER1() {
+    on_interrupt::<pac::TIMER1>();
+}
+
+#[interrupt]
+fn TIMER2() {
+    on_interrupt::<pac::TIMER2>();
 }
@@ -0,0 +1,404 @@
+//! Self-describing payload format
+//!
+//! The payload starts with the format version followed by records. Each record carries the kind of
+//! the quantity, its unit, the format of the value and the value itself:
+//!
+//! `[kind u8][unit u8][format u8][value]`
+//!
+//! The upper two bits of the kind mark the value as minimum, maximum or mean of several samples.
+//!
+//! The lower three bits of the format select the encoding of the value, bit 3 selects the base of
+//! the scale (0: 10, 1: 2) and the upper four bits hold the exponent (-8..7) the value is scaled
+//! with. A node only sends the quantities it has and new kinds can be decoded without changing the
+//! format.
+//!
+//! Version 1 of the format only supported decimal scales with a five bit exponent (-16..15) in the
+//! upper bits of the format; it is still decoded.
+
+/// Version of the format
+pub const VERSION: u8 = 2;
+/// Version of the format with decimal scales only
+const VERSION_DECIMAL: u8 = 1;
+
+pub const KIND_TEMPERATURE: u8 = 1;
+pub const KIND_HUMIDITY: u8 = 2;
+pub const KIND_BATTERY_VOLTAGE: u8 = 3;
+pub const KIND_TX_POWER: u8 = 4;
+pub const KIND_MAGNETIC_FIELD_X: u8 = 5;
+pub const KIND_MAGNETIC_FIELD_Y: u8 = 6;
+pub const KIND_MAGNETIC_FIELD_Z: u8 = 7;
+pub const KIND_ACCELERATION_X: u8 = 8;
+pub const KIND_ACCELERATION_Y: u8 = 9;
+pub const KIND_ACCELERATION_Z: u8 = 10;
+pub const KIND_SENSOR_ID: u8 = 11;
+/// Time of the measurement
+pub const KIND_TIME: u8 = 12;
+/// Reason for sending the measurement (see `report::Reason`)
+pub const KIND_REPORT_REASON: u8 = 13;
+/// Number of samples the statistics were computed from
+pub const KIND_SAMPLE_COUNT: u8 = 14;
+/// Samples in the flash log of the node that have not been acknowledged
+pub const KIND_LOG_PENDING: u8 = 15;
+/// Samples that can be written to the flash log before unacknowledged samples are overwritten
+pub const KIND_LOG_FREE: u8 = 16;
+/// Highest number of erases of a flash page of the log
+pub const KIND_FLASH_ERASES: u8 = 17;
+
+/// Statistic of a quantity over several samples held in the upper two bits of the kind
+pub const STATISTIC_MASK: u8 = 0xC0;
+pub const STATISTIC_MIN: u8 = 0x40;
+pub const STATISTIC_MAX: u8 = 0x80;
+pub const STATISTIC_MEAN: u8 = 0xC0;
+
+pub const UNIT_NONE: u8 = 0;
+pub const UNIT_DEGREE_CELSIUS: u8 = 1;
+pub const UNIT_PERCENT_RH: u8 = 2;
+pub const UNIT_VOLT: u8 = 3;
+pub const UNIT_DBM: u8 = 4;
+pub const UNIT_TESLA: u8 = 5;
+pub const UNIT_STANDARD_GRAVITY: u8 = 6;
+/// Seconds since the Unix epoch
+pub const UNIT_SECOND: u8 = 7;
+
+/// Length of the header of a record
+pub const RECORD_HEADER_LEN: usize = 3;
+
+#[derive(Clone, Copy)]
+pub enum Scale {
+    /// value times 10^exponent
+    Decimal(i8),
+    /// value times 2^exponent
+    Binary(i8),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    BufferTooShort,
+    Scale,
+}
+
+#[derive(Clone, Copy)]
+pub enum Value {
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    F32(f32),
+}
+
+impl Value {
+    fn encoding(&self) -> u8 {
+        match self {
+            Value::I8(_) => 0,
+            Value::U8(_) => 1,
+            Value::I16(_) => 2,
+            Value::U16(_) => 3,
+            Value::I32(_) => 4,
+            Value::U32(_) => 5,
+            Value::F32(_) => 6,
+        }
+    }
+
+    fn len(&self) -> usize {
+        encoding_len(self.encoding()).unwrap_or(0)
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        match self {
+            Value::I8(value) => buffer[0] = *value as u8,
+            Value::U8(value) => buffer[0] = *value,
+            Value::I16(value) => buffer.copy_from_slice(&value.to_le_bytes()[..]),
+            Value::U16(value) => buffer.copy_from_slice(&value.to_le_bytes()[..]),
+            Value::I32(value) => buffer.copy_from_slice(&value.to_le_bytes()[..]),
+            Value::U32(value) => buffer.copy_from_slice(&value.to_le_bytes()[..]),
+            Value::F32(value) => buffer.copy_from_slice(&value.to_le_bytes()[..]),
+        }
+    }
+
+    fn read(encoding: u8, data: &[u8]) -> Option<Value> {
+        let value = match encoding {
+            0 => Value::I8(data[0] as i8),
+            1 => Value::U8(data[0]),
+            2 => Value::I16(i16::from_le_bytes([data[0], data[1]])),
+            3 => Value::U16(u16::from_le_bytes([data[0], data[1]])),
+            4 => Value::I32(i32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+            5 => Value::U32(u32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+            6 => Value::F32(f32::from_le_bytes([data[0], data[1], data[2], data[3]])),
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn as_f32(&self) -> f32 {
+        match self {
+            Value::I8(value) => *value as f32,
+            Value::U8(value) => *value as f32,
+            Value::I16(value) => *value as f32,
+            Value::U16(value) => *value as f32,
+            Value::I32(value) => *value as f32,
+            Value::U32(value) => *value as f32,
+            Value::F32(value) => *value,
+        }
+    }
+}
+
+/// Measured quantity
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub kind: u8,
+    pub unit: u8,
+    pub scale: Scale,
+    pub value: Value,
+}
+
+impl Record {
+    /// Create a record of a value without scale.
+    pub fn new(kind: u8, unit: u8, value: Value) -> Record {
+        Record {
+            kind,
+            unit,
+            scale: Scale::Decimal(0),
+            value,
+        }
+    }
+
+    /// Create a temperature record in centi-degrees.
+    pub fn temperature(celsius: f32) -> Record {
+        Record {
+            kind: KIND_TEMPERATURE,
+            unit: UNIT_DEGREE_CELSIUS,
+            scale: Scale::Decimal(-2),
+            value: Value::I16(
+                round(celsius * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+            ),
+        }
+    }
+
+    /// Create a humidity record in steps of 0.5 %RH.
+    ///
+    /// The value is limited to 0-100 %RH; the SHT4x reports values outside of this range, which
+    /// Sensirion recommends to crop.
+    pub fn humidity(percent: f32) -> Record {
+        Record {
+            kind: KIND_HUMIDITY,
+            unit: UNIT_PERCENT_RH,
+            scale: Scale::Binary(-1),
+            value: Value::U8(round(percent * 2.0).clamp(0, 200) as u8),
+        }
+    }
+
+    /// Create a battery voltage record in mV.
+    pub fn battery_voltage(volt: f32) -> Record {
+        Record {
+            kind: KIND_BATTERY_VOLTAGE,
+            unit: UNIT_VOLT,
+            scale: Scale::Decimal(-3),
+            value: Value::U16(round(volt * 1000.0).clamp(0, u16::MAX as i32) as u16),
+        }
+    }
+
+    /// Mark the record as a statistic (`STATISTIC_*`) of the quantity.
+    pub fn with_statistic(self, statistic: u8) -> Record {
+        Record {
+            kind: self.kind & !STATISTIC_MASK | statistic & STATISTIC_MASK,
+            ..self
+        }
+    }
+
+    /// Get the value in the unit of the record.
+    pub fn scaled(&self) -> f32 {
+        let (base, exponent) = match self.scale {
+            Scale::Decimal(exponent) => (10.0, exponent),
+            Scale::Binary(exponent) => (2.0, exponent),
+        };
+        let mut value = self.value.as_f32();
+
+        for _ in 0..exponent.abs() {
+            match exponent > 0 {
+                true => value *= base,
+                false => value /= base,
+            }
+        }
+
+        value
+    }
+}
+
+/// Writes records into a buffer, starting with the version.
+pub struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Result<Writer<'a>, Error> {
+        if buffer.is_empty() {
+            return Err(Error::BufferTooShort);
+        }
+
+        buffer[0] = VERSION;
+
+        Ok(Writer { buffer, len: 1 })
+    }
+
+    /// Continue writing records after a payload of `len` bytes.
+    pub fn append(buffer: &'a mut [u8], len: usize) -> Result<Writer<'a>, Error> {
+        if len == 0 || len > buffer.len() || buffer[0] != VERSION {
+            return Err(Error::BufferTooShort);
+        }
+
+        Ok(Writer { buffer, len })
+    }
+
+    pub fn push(&mut self, record: &Record) -> Result<(), Error> {
+        let len = RECORD_HEADER_LEN + record.value.len();
+
+        let scale = match record.scale {
+            Scale::Decimal(exponent @ -8..=7) => (exponent as u8) << 4,
+            Scale::Binary(exponent @ -8..=7) => (exponent as u8) << 4 | 0x08,
+            _ => return Err(Error::Scale),
+        };
+
+        if self.len + len > self.buffer.len() {
+            return Err(Error::BufferTooShort);
+        }
+
+        let data = &mut self.buffer[self.len..self.len + len];
+
+        data[0] = record.kind;
+        data[1] = record.unit;
+        data[2] = scale | record.value.encoding();
+        record.value.write(&mut data[RECORD_HEADER_LEN..]);
+        self.len += len;
+
+        Ok(())
+    }
+
+    /// Number of bytes written including the version
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// No records have been written.
+    pub fn is_empty(&self) -> bool {
+        self.len <= 1
+    }
+}
+
+/// Iterator over the records of a payload
+///
+/// The iteration stops at the end of the payload or at the first malformed record.
+pub struct Records<'a> {
+    data: &'a [u8],
+    version: u8,
+}
+
+impl<'a> Records<'a> {
+    /// Returns `None` if the payload does not start with a supported version.
+    pub fn from_bytes(data: &'a [u8]) -> Option<Records<'a>> {
+        match data.first() {
+            Some(&version) if version == VERSION || version == VERSION_DECIMAL => Some(Records {
+                data: &data[1..],
+                version,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        if self.data.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+
+        let format = self.data[2];
+        let encoding = format & 0x07;
+        let len = RECORD_HEADER_LEN + encoding_len(encoding)?;
+
+        if self.data.len() < len {
+            self.data = &[];
+
+            return None;
+        }
+
+        let record = Record {
+            kind: self.data[0],
+            unit: self.data[1],
+            // arithmetic shifts keep the sign of the exponent
+            scale: match (self.version, format & 0x08 == 0x08) {
+                (VERSION_DECIMAL, _) => Scale::Decimal((format as i8) >> 3),
+                (_, false) => Scale::Decimal((format as i8) >> 4),
+                (_, true) => Scale::Binary((format as i8) >> 4),
+            },
+            value: Value::read(encoding, &self.data[RECORD_HEADER_LEN..len])?,
+        };
+
+        self.data = &self.data[len..];
+
+        Some(record)
+    }
+}
+
+/// Round to the nearest integer (`f32::round` is not available without `std`).
+fn round(value: f32) -> i32 {
+    match value < 0.0 {
+        true => (value - 0.5) as i32,
+        false => (value + 0.5) as i32,
+    }
+}
+
+fn encoding_len(encoding: u8) -> Option<usize> {
+    match encoding {
+        0 | 1 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn humidity_limited() {
+        assert!(matches!(Record::humidity(45.3).value, Value::U8(91)));
+        assert!(matches!(Record::humidity(-6.0).value, Value::U8(0)));
+        assert!(matches!(Record::humidity(119.0).value, Value::U8(200)));
+    }
+
+    #[test]
+    fn battery_voltage_limited() {
+        assert!(matches!(
+            Record::battery_voltage(2.9876).value,
+            Value::U16(2988)
+        ));
+        assert!(matches!(Record::battery_voltage(-0.1).value, Value::U16(0)));
+        assert!(matches!(
+            Record::battery_voltage(70.0).value,
+            Value::U16(u16::MAX)
+        ));
+    }
+
+    #[test]
+    fn temperature_limited() {
+        assert!(matches!(
+            Record::temperature(-21.537).value,
+            Value::I16(-2154)
+        ));
+        assert!(matches!(
+            Record::temperature(400.0).value,
+            Value::I16(i16::MAX)
+        ));
+        assert!(matches!(
+            Record::temperature(-400.0).value,
+            Value::I16(i16::MIN)
+        ));
+    }
+}
@@ -1,11 +1,48 @@
+use super::executor;
+use super::p0;
+use super::timer;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use cortex_m::interrupt;
+use embedded_hal::blocking::i2c;
 use nrf52810_pac as pac;
 use pac::interrupt;
 
-pub struct Twim<'a> {
+/// Default time a transfer may take (µs)
+pub const DEFAULT_TIMEOUT_MUS: u32 = 10_000;
+
+/// CPU cycles per µs at 64 MHz
+const CYCLES_PER_MUS: u32 = 64;
+/// Half period of the clock during bus recovery (µs, 100 kHz)
+const RECOVERY_HALF_PERIOD_MUS: u32 = 5;
+/// Clock pulses after which a device must have released SDA
+const RECOVERY_CLOCKS: usize = 9;
+/// Time the peripheral gets to stop after an error or a timeout (µs)
+const STOP_TIMEOUT_MUS: u32 = 100;
+/// Start of the RAM; EasyDMA cannot read data below (in flash)
+const RAM_START: u32 = 0x2000_0000;
+/// Longest data outside the RAM that can be written (copied to the stack)
+const COPY_BUFFER_LEN: usize = 32;
+
+/// Waker of the transfer in progress
+static WAKER: executor::WakerSlot = executor::WakerSlot::new();
+
+// bits of the ERRORSRC register
+const ERRORSRC_OVERRUN: u32 = 1 << 0;
+const ERRORSRC_ANACK: u32 = 1 << 1;
+const ERRORSRC_DNACK: u32 = 1 << 2;
+
+/// TWI master
+///
+/// The timer measures the timeout of the transfers; it must not be used otherwise while the TWI
+/// master exists.
+pub struct Twim<'a, T: timer::Instance = pac::TIMER1> {
     twim: &'a mut pac::TWIM0,
-    p0: &'a mut pac::P0,
-    scl: usize,
-    sda: usize,
+    timer: timer::Timer<'a, T>,
+    scl: &'a mut p0::Pin<p0::OpenDrain>,
+    sda: &'a mut p0::Pin<p0::OpenDrain>,
+    timeout: u32,
 }
 
 pub enum Frequency {
@@ -14,53 +51,43 @@ pub enum Frequency {
     K400,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Error {
-    Transmit,
+    /// the address was not acknowledged (no device or the device is busy)
+    AddressNack,
+    /// a data byte was not acknowledged
+    DataNack,
+    /// a byte was received before the previous one was stored
+    Overrun,
+    /// the transfer did not complete in time (e.g. SCL or SDA held low)
+    Timeout,
+    /// SDA is still held low after the bus recovery
+    BusStuck,
+    /// the data to write is not in RAM and longer than `COPY_BUFFER_LEN`
+    BufferNotInRam,
 }
 
-impl<'a> Twim<'a> {
+impl<'a, T: timer::Instance> Twim<'a, T> {
+    /// Create a new TWI master.
+    ///
+    /// The pins are expected to be open drain (see `p0::Pin::into_open_drain`), usually with the
+    /// pull-up enabled unless the board has external pull-ups.
     pub fn new(
         twim: &'a mut pac::TWIM0,
+        timer: &'a mut T,
         nvic: &mut pac::NVIC,
-        p0: &'a mut pac::P0,
-        scl: usize,
-        sda: usize,
+        scl: &'a mut p0::Pin<p0::OpenDrain>,
+        sda: &'a mut p0::Pin<p0::OpenDrain>,
         freq: Frequency,
-    ) -> Twim<'a> {
-        // configure pins
-        p0.pin_cnf[scl].write(|mut w| {
-            w.dir()
-                .input()
-                .pull()
-                .pullup()
-                .drive()
-                .s0d1()
-                .input()
-                .connect()
-                .sense()
-                .disabled()
-        });
-        p0.pin_cnf[sda].write(|mut w| {
-            w.dir()
-                .input()
-                .pull()
-                .pullup()
-                .drive()
-                .s0d1()
-                .input()
-                .connect()
-                .sense()
-                .disabled()
-        });
+    ) -> Twim<'a, T> {
         #[allow(deprecated)]
         nvic.enable(pac::interrupt::TWIM0_TWIS0_TWI0);
         twim.psel
             .scl
-            .write(|w| unsafe { w.pin().bits(scl as u8).connect().connected() });
+            .write(|w| unsafe { w.pin().bits(scl.pin() as u8).connect().connected() });
         twim.psel
             .sda
-            .write(|w| unsafe { w.pin().bits(sda as u8).connect().connected() });
+            .write(|w| unsafe { w.pin().bits(sda.pin() as u8).connect().connected() });
 
         match freq {
             Frequency::K100 => twim.frequency.write(|w| w.frequency().k100()),
@@ -68,10 +95,18 @@ impl<'a> Twim<'a> {
             Frequency::K400 => twim.frequency.write(|w| w.frequency().k400()),
         }
 
-        twim.shorts
-            .write(|w| w.lastrx_stop().enabled().lasttx_stop().enabled());
+        Twim {
+            twim,
+            timer: timer::Timer::new(timer, nvic),
+            scl,
+            sda,
+            timeout: DEFAULT_TIMEOUT_MUS,
+        }
+    }
 
-        Twim { twim, p0, scl, sda }
+    /// Set the time a transfer may take before `wait` gives up (µs; 0 for no timeout).
+    pub fn set_timeout_mus(&mut self, timeout: u32) {
+        self.timeout = timeout;
     }
 
     /// Start writing to the TWI interface.
@@ -95,6 +130,9 @@ impl<'a> Twim<'a> {
             .maxcnt
             .write(|w| unsafe { w.maxcnt().bits(buffer.len() as u16) });
 
+        // stop after the last byte
+        self.twim.shorts.write(|w| w.lasttx_stop().enabled());
+
         // set interrupts
         self.twim
             .intenset
@@ -135,6 +173,9 @@ impl<'a> Twim<'a> {
             .maxcnt
             .write(|w| unsafe { w.maxcnt().bits(buffer.len() as u16) });
 
+        // stop after the last byte
+        self.twim.shorts.write(|w| w.lastrx_stop().enabled());
+
         // set interrupts
         self.twim
             .intenset
@@ -155,45 +196,346 @@ impl<'a> Twim<'a> {
             .write(|w| w.tasks_startrx().trigger());
     }
 
-    pub fn wait(&mut self) -> Result<(), Error> {
-        while self
-            .twim
+    /// Start writing to the TWI interface and continue with reading after a repeated start.
+    ///
+    /// The bus is not released in between, as required for reading registers of some devices.
+    /// This function is not safe in the sense that the buffers could be changed or dropped
+    /// before `wait` has finished.
+    pub fn start_write_read(&mut self, address: u8, tx_buffer: &[u8], rx_buffer: &mut [u8]) {
+        // set address
+        self.twim
+            .address
+            .write(|w| unsafe { w.address().bits(address) });
+
+        // set buffers
+        self.twim
+            .txd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(tx_buffer.as_ptr() as u32) });
+        self.twim
+            .txd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(tx_buffer.len() as u16) });
+        self.twim
+            .rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(rx_buffer.as_ptr() as u32) });
+        self.twim
+            .rxd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(rx_buffer.len() as u16) });
+
+        // read after the last byte written and stop after the last byte read
+        self.twim
+            .shorts
+            .write(|w| w.lasttx_startrx().enabled().lastrx_stop().enabled());
+
+        // set interrupts
+        self.twim
+            .intenset
+            .write(|w| w.stopped().set().error().set());
+
+        // enable
+        self.twim.enable.write(|w| w.enable().enabled());
+
+        // trigger write
+        self.twim
+            .events_error
+            .write(|w| w.events_error().not_generated());
+        self.twim
             .events_stopped
-            .read()
-            .events_stopped()
-            .is_not_generated()
-            && self
-                .twim
+            .write(|w| w.events_stopped().not_generated());
+        self.twim
+            .tasks_starttx
+            .write(|w| w.tasks_starttx().trigger());
+    }
+
+    /// Write to a device and read its response in one transaction.
+    pub fn write_read(
+        &mut self,
+        address: u8,
+        tx_buffer: &[u8],
+        rx_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.start_write_read(address, tx_buffer, rx_buffer);
+        self.wait()
+    }
+
+    /// Wait for the transfer to complete.
+    ///
+    /// The CPU sleeps with `wfi` until the interrupt of the peripheral or of the timer. A device
+    /// holding a line low results in `Error::Timeout` instead of a hang; `recover` frees the bus
+    /// afterwards.
+    pub fn wait(&mut self) -> Result<(), Error> {
+        let completed = self.sleep(self.timeout);
+
+        self.finish(completed)
+    }
+
+    /// Write to a device without blocking the executor.
+    ///
+    /// The timeout does not apply; the transfer is stopped if the future is dropped.
+    pub async fn write_async(&mut self, address: u8, buffer: &[u8]) -> Result<(), Error> {
+        self.start_write(address, buffer);
+
+        Transfer {
+            twim: self,
+            done: false,
+        }
+        .await
+    }
+
+    /// Read from a device without blocking the executor.
+    ///
+    /// The timeout does not apply; the transfer is stopped if the future is dropped.
+    pub async fn read_async(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.start_read(address, buffer);
+
+        Transfer {
+            twim: self,
+            done: false,
+        }
+        .await
+    }
+
+    /// Write to a device and read its response in one transaction without blocking the executor.
+    ///
+    /// The timeout does not apply; the transfer is stopped if the future is dropped.
+    pub async fn write_read_async(
+        &mut self,
+        address: u8,
+        tx_buffer: &[u8],
+        rx_buffer: &mut [u8],
+    ) -> Result<(), Error> {
+        self.start_write_read(address, tx_buffer, rx_buffer);
+
+        Transfer {
+            twim: self,
+            done: false,
+        }
+        .await
+    }
+
+    /// Clean up after a transfer and get its result.
+    ///
+    /// # Arguments
+    ///
+    /// * `completed` - the transfer ended (successfully or with an error) before the timeout
+    ///
+    fn finish(&mut self, completed: bool) -> Result<(), Error> {
+        if !completed || self.twim.events_error.read().events_error().is_generated() {
+            // the peripheral does not stop by itself after an error
+            self.twim
                 .events_error
-                .read()
-                .events_error()
-                .is_not_generated()
-        {
-            cortex_m::asm::wfi();
+                .write(|w| w.events_error().not_generated());
+            self.twim.tasks_stop.write(|w| w.tasks_stop().trigger());
+            self.sleep(STOP_TIMEOUT_MUS);
         }
 
-        let flag_error = self.twim.events_error.read().events_error().is_generated();
+        let errorsrc = self.twim.errorsrc.read().bits();
 
+        self.twim.errorsrc.write(|w| unsafe { w.bits(errorsrc) });
         self.twim
             .events_error
             .write(|w| w.events_error().not_generated());
         self.twim
             .events_stopped
             .write(|w| w.events_stopped().not_generated());
+        self.twim.enable.write(|w| w.enable().disabled());
 
-        /////
-        let tmp = self.twim.errorsrc.read().bits();
-        /////
+        if !completed {
+            return Err(Error::Timeout);
+        }
 
+        if errorsrc & ERRORSRC_ANACK != 0 {
+            Err(Error::AddressNack)
+        } else if errorsrc & ERRORSRC_DNACK != 0 {
+            Err(Error::DataNack)
+        } else if errorsrc & ERRORSRC_OVERRUN != 0 {
+            Err(Error::Overrun)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Free the bus, e.g. after a reset of the node in the middle of a transfer.
+    ///
+    /// SCL is clocked manually until the device holding SDA low releases it (at most nine clock
+    /// pulses) and a STOP condition is issued. The pins are handed back to the peripheral
+    /// afterwards.
+    pub fn recover(&mut self) -> Result<(), Error> {
         self.twim.enable.write(|w| w.enable().disabled());
+        self.half_period();
+
+        for _ in 0..RECOVERY_CLOCKS {
+            if self.sda.is_high() {
+                break;
+            }
+
+            self.scl.set_low();
+            self.half_period();
+            self.scl.set_high();
+            self.half_period();
+        }
+
+        // STOP: SDA rises while SCL is high
+        self.scl.set_low();
+        self.half_period();
+        self.sda.set_low();
+        self.half_period();
+        self.scl.set_high();
+        self.half_period();
+        self.sda.set_high();
+        self.half_period();
+
+        match self.sda.is_high() {
+            true => Ok(()),
+            false => Err(Error::BusStuck),
+        }
+    }
+
+    /// The transfer ended or an error occurred.
+    fn is_done(&self) -> bool {
+        self.twim
+            .events_stopped
+            .read()
+            .events_stopped()
+            .is_generated()
+            || self.twim.events_error.read().events_error().is_generated()
+    }
+
+    /// Sleep until the end of the transfer or an error.
+    ///
+    /// Returns `false` if neither happened before the timeout (µs; 0 for no timeout).
+    fn sleep(&mut self, timeout: u32) -> bool {
+        if timeout > 0 {
+            self.timer.set_timeout_mus(timeout);
+            self.timer.start();
+        }
+
+        let completed = loop {
+            let state = interrupt::free(|_| {
+                // the interrupt handler disables the interrupts after waking up
+                self.twim
+                    .intenset
+                    .write(|w| w.stopped().set().error().set());
+
+                if self.is_done() {
+                    Some(true)
+                } else if timeout > 0 && self.timer.is_expired() {
+                    Some(false)
+                } else {
+                    // interrupts are masked, so that an event in between is not missed; `wfi` also
+                    // returns on masked interrupts
+                    cortex_m::asm::wfi();
+
+                    None
+                }
+            });
+
+            if let Some(completed) = state {
+                break completed;
+            }
+        };
+
+        self.timer.stop();
+
+        completed
+    }
+
+    fn half_period(&self) {
+        cortex_m::asm::delay(RECOVERY_HALF_PERIOD_MUS * CYCLES_PER_MUS);
+    }
+}
+
+/// Future of a transfer started on a `Twim`
+struct Transfer<'t, 'a, T: timer::Instance> {
+    twim: &'t mut Twim<'a, T>,
+    done: bool,
+}
+
+impl<'t, 'a, T: timer::Instance> Future for Transfer<'t, 'a, T> {
+    type Output = Result<(), Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        WAKER.register(cx.waker());
+        // the interrupt handler disables the interrupts after waking the future
+        self.twim
+            .twim
+            .intenset
+            .write(|w| w.stopped().set().error().set());
 
-        match flag_error {
-            false => Ok(()),
-            true => Err(Error::Transmit),
+        if !self.twim.is_done() {
+            return Poll::Pending;
         }
+
+        self.done = true;
+
+        Poll::Ready(self.twim.finish(true))
     }
 }
 
+impl<'t, 'a, T: timer::Instance> Drop for Transfer<'t, 'a, T> {
+    fn drop(&mut self) {
+        // the buffers may be gone after the future
+        if !self.done {
+            self.twim
+                .twim
+                .tasks_stop
+                .write(|w| w.tasks_stop().trigger());
+            self.twim.sleep(STOP_TIMEOUT_MUS);
+            self.twim.finish(true).ok();
+        }
+    }
+}
+
+impl<'a, T: timer::Instance> i2c::Write for Twim<'a, T> {
+    type Error = Error;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        let mut copy = [0u8; COPY_BUFFER_LEN];
+        let bytes = in_ram(bytes, &mut copy)?;
+
+        self.start_write(address, bytes);
+        self.wait()
+    }
+}
+
+impl<'a, T: timer::Instance> i2c::Read for Twim<'a, T> {
+    type Error = Error;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        self.start_read(address, buffer);
+        self.wait()
+    }
+}
+
+impl<'a, T: timer::Instance> i2c::WriteRead for Twim<'a, T> {
+    type Error = Error;
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), Error> {
+        let mut copy = [0u8; COPY_BUFFER_LEN];
+        let bytes = in_ram(bytes, &mut copy)?;
+
+        Twim::write_read(self, address, bytes, buffer)
+    }
+}
+
+/// Get data EasyDMA can read; data outside the RAM (e.g. constant commands) is copied.
+fn in_ram<'b>(bytes: &'b [u8], copy: &'b mut [u8]) -> Result<&'b [u8], Error> {
+    if bytes.as_ptr() as u32 >= RAM_START {
+        return Ok(bytes);
+    }
+
+    if bytes.len() > copy.len() {
+        return Err(Error::BufferNotInRam);
+    }
+
+    copy[..bytes.len()].copy_from_slice(bytes);
+
+    Ok(&copy[..bytes.len()])
+}
+
 // impl<'a> Drop for Twim<'a> {
 //     fn drop(&mut self) {
 //         // reset pins
@@ -221,4 +563,5 @@ fn TWIM0_TWIS0_TWI0() {
         .TWIM0
         .intenclr
         .write(|w| w.stopped().clear().error().clear());
+    WAKER.wake();
 }
@@ -0,0 +1,443 @@
+//! UARTE driver with transmission and reception in the background
+//!
+//! Bytes to send are queued in a ring buffer and sent by EasyDMA chunk by chunk. Reception
+//! alternates between two DMA buffers, so that no byte is lost while the interrupt handler moves the
+//! bytes of the full buffer into a ring buffer. The application calls `on_interrupt` from its
+//! handler of `UARTE0_UART0`; the driver does not define the handler, as RTIC binaries bind the
+//! interrupt themselves.
+
+use super::p0;
+use core::cell::RefCell;
+use core::sync::atomic::{compiler_fence, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use nrf52810_pac as pac;
+
+/// Length of the transmit and receive ring buffers
+const RING_LEN: usize = 256;
+/// Length of each of the two DMA buffers for reception
+const RX_CHUNK_LEN: usize = 16;
+/// CPU cycles per µs at 64 MHz
+const CYCLES_PER_MUS: u32 = 64;
+/// Time the peripheral gets to stop the reception (µs)
+const STOP_TIMEOUT_MUS: u32 = 1_000;
+
+static STATE: Mutex<RefCell<State>> = Mutex::new(RefCell::new(State::new()));
+
+pub enum Baudrate {
+    Baud9600,
+    Baud19200,
+    Baud38400,
+    Baud57600,
+    Baud115200,
+    Baud230400,
+    Baud460800,
+    Baud1M,
+}
+
+struct Ring {
+    data: [u8; RING_LEN],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Ring {
+        Ring {
+            data: [0; RING_LEN],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Append as many bytes as fit; returns the number of bytes appended.
+    fn push(&mut self, bytes: &[u8]) -> usize {
+        let count = bytes.len().min(RING_LEN - self.len);
+
+        for byte in &bytes[..count] {
+            self.data[(self.start + self.len) % RING_LEN] = *byte;
+            self.len += 1;
+        }
+
+        count
+    }
+
+    /// Take as many bytes as available; returns the number of bytes taken.
+    fn pop(&mut self, bytes: &mut [u8]) -> usize {
+        let count = bytes.len().min(self.len);
+
+        for byte in &mut bytes[..count] {
+            *byte = self.data[self.start];
+            self.consume(1);
+        }
+
+        count
+    }
+
+    /// Bytes from the start up to the end of the ring or of the data
+    fn contiguous(&self) -> &[u8] {
+        &self.data[self.start..(self.start + self.len).min(RING_LEN)]
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.start = (self.start + count) % RING_LEN;
+        self.len -= count;
+    }
+}
+
+struct State {
+    tx: Ring,
+    /// bytes at the start of `tx` being sent
+    sending: usize,
+    rx: Ring,
+    rx_buffers: [[u8; RX_CHUNK_LEN]; 2],
+    /// DMA buffer receiving currently
+    rx_current: usize,
+}
+
+impl State {
+    const fn new() -> State {
+        State {
+            tx: Ring::new(),
+            sending: 0,
+            rx: Ring::new(),
+            rx_buffers: [[0; RX_CHUNK_LEN]; 2],
+            rx_current: 0,
+        }
+    }
+}
+
+pub struct Uarte<'a> {
+    uarte: &'a mut pac::UARTE0,
+    // borrowed while the peripheral uses them
+    _txd: &'a mut p0::Pin<p0::Output>,
+    _rxd: &'a mut p0::Pin<p0::Input>,
+}
+
+impl<'a> Uarte<'a> {
+    /// Create a new UARTE.
+    ///
+    /// The transmit pin is expected to be an output (see `p0::Pin::into_output`), the receive pin an
+    /// input; the transmit pin is set to the idle level of the line (high).
+    pub fn new(
+        uarte: &'a mut pac::UARTE0,
+        nvic: &mut pac::NVIC,
+        txd: &'a mut p0::Pin<p0::Output>,
+        rxd: &'a mut p0::Pin<p0::Input>,
+        baudrate: Baudrate,
+    ) -> Uarte<'a> {
+        txd.set_high();
+
+        #[allow(deprecated)]
+        nvic.enable(pac::interrupt::UARTE0_UART0);
+        uarte
+            .psel
+            .txd
+            .write(|w| unsafe { w.pin().bits(txd.pin() as u8).connect().connected() });
+        uarte
+            .psel
+            .rxd
+            .write(|w| unsafe { w.pin().bits(rxd.pin() as u8).connect().connected() });
+
+        match baudrate {
+            Baudrate::Baud9600 => uarte.baudrate.write(|w| w.baudrate().baud9600()),
+            Baudrate::Baud19200 => uarte.baudrate.write(|w| w.baudrate().baud19200()),
+            Baudrate::Baud38400 => uarte.baudrate.write(|w| w.baudrate().baud38400()),
+            Baudrate::Baud57600 => uarte.baudrate.write(|w| w.baudrate().baud57600()),
+            Baudrate::Baud115200 => uarte.baudrate.write(|w| w.baudrate().baud115200()),
+            Baudrate::Baud230400 => uarte.baudrate.write(|w| w.baudrate().baud230400()),
+            Baudrate::Baud460800 => uarte.baudrate.write(|w| w.baudrate().baud460800()),
+            Baudrate::Baud1M => uarte.baudrate.write(|w| w.baudrate().baud1m()),
+        }
+
+        uarte
+            .config
+            .write(|w| w.hwfc().disabled().parity().excluded());
+        uarte.enable.write(|w| w.enable().enabled());
+
+        Uarte {
+            uarte,
+            _txd: txd,
+            _rxd: rxd,
+        }
+    }
+
+    /// Queue bytes for sending; waits with `wfi` while the ring buffer is full.
+    pub fn write(&mut self, bytes: &[u8]) {
+        let mut bytes = bytes;
+
+        while !bytes.is_empty() {
+            let count = interrupt::free(|cs| {
+                let mut state = STATE.borrow(cs).borrow_mut();
+                let count = state.tx.push(bytes);
+
+                start_tx(self.uarte, &mut state);
+
+                // interrupts are masked, so that the end of the transmission is not missed; `wfi`
+                // also returns on masked interrupts
+                if count == 0 {
+                    cortex_m::asm::wfi();
+                }
+
+                count
+            });
+
+            bytes = &bytes[count..];
+        }
+    }
+
+    /// Wait until all queued bytes have been sent.
+    pub fn flush(&mut self) {
+        while interrupt::free(|cs| {
+            let state = STATE.borrow(cs).borrow();
+            let busy = state.tx.len > 0;
+
+            if busy {
+                cortex_m::asm::wfi();
+            }
+
+            busy
+        }) {}
+    }
+
+    /// Start receiving in the background.
+    pub fn start_rx(&mut self) {
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            state.rx_current = 0;
+            set_rx_buffer(self.uarte, &mut state.rx_buffers[0]);
+        });
+
+        // continue with the other buffer whenever one is full
+        self.uarte.shorts.write(|w| w.endrx_startrx().enabled());
+        self.uarte
+            .events_endrx
+            .write(|w| w.events_endrx().not_generated());
+        self.uarte
+            .events_rxstarted
+            .write(|w| w.events_rxstarted().not_generated());
+        self.uarte
+            .events_rxto
+            .write(|w| w.events_rxto().not_generated());
+        self.uarte
+            .intenset
+            .write(|w| w.endrx().set().rxstarted().set());
+        compiler_fence(Ordering::SeqCst);
+        self.uarte
+            .tasks_startrx
+            .write(|w| w.tasks_startrx().trigger());
+    }
+
+    /// Stop receiving; bytes still in the DMA buffer and the FIFO are kept.
+    pub fn stop_rx(&mut self) {
+        let uarte: &pac::UARTE0 = self.uarte;
+
+        interrupt::free(|cs| {
+            let mut state = STATE.borrow(cs).borrow_mut();
+
+            uarte
+                .intenclr
+                .write(|w| w.endrx().clear().rxstarted().clear());
+            uarte.shorts.write(|w| w.endrx_startrx().disabled());
+            uarte.tasks_stoprx.write(|w| w.tasks_stoprx().trigger());
+            poll(|| uarte.events_rxto.read().events_rxto().is_generated());
+            uarte.events_rxto.write(|w| w.events_rxto().not_generated());
+
+            // the buffer received into when stopping
+            if uarte.events_endrx.read().events_endrx().is_generated() {
+                end_rx(uarte, &mut state);
+            }
+
+            // the bytes left in the FIFO
+            let current = state.rx_current;
+
+            set_rx_buffer(uarte, &mut state.rx_buffers[current]);
+            uarte.tasks_flushrx.write(|w| w.tasks_flushrx().trigger());
+
+            if poll(|| uarte.events_endrx.read().events_endrx().is_generated()) {
+                end_rx(uarte, &mut state);
+            }
+
+            uarte
+                .events_rxstarted
+                .write(|w| w.events_rxstarted().not_generated());
+        });
+    }
+
+    /// Take the received bytes; returns the number of bytes copied into `buffer`.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        interrupt::free(|cs| STATE.borrow(cs).borrow_mut().rx.pop(buffer))
+    }
+
+    /// Wait until `buffer` is full or no byte was received for `timeout` µs.
+    ///
+    /// The reception must have been started with `start_rx`; bytes still in a DMA buffer are
+    /// collected at the timeout. Returns the number of bytes copied into `buffer`.
+    pub fn read_timeout(&mut self, buffer: &mut [u8], timeout: u32) -> usize {
+        let mut count = 0;
+        let mut idle = 0;
+
+        while count < buffer.len() && idle < timeout {
+            match self.read(&mut buffer[count..]) {
+                0 => {
+                    cortex_m::asm::delay(CYCLES_PER_MUS);
+                    idle += 1;
+                }
+                received => {
+                    count += received;
+                    idle = 0;
+                }
+            }
+        }
+
+        if count < buffer.len() {
+            self.stop_rx();
+            count += self.read(&mut buffer[count..]);
+            self.start_rx();
+        }
+
+        count
+    }
+
+    /// Power the peripheral down; queued bytes are sent and the reception is stopped before.
+    pub fn disable(&mut self) {
+        self.flush();
+        self.stop_rx();
+
+        let uarte: &pac::UARTE0 = self.uarte;
+
+        // the transmitter keeps running after the last byte until it is stopped
+        uarte.tasks_stoptx.write(|w| w.tasks_stoptx().trigger());
+        poll(|| {
+            uarte
+                .events_txstopped
+                .read()
+                .events_txstopped()
+                .is_generated()
+        });
+        uarte
+            .events_txstopped
+            .write(|w| w.events_txstopped().not_generated());
+        uarte.enable.write(|w| w.enable().disabled());
+    }
+
+    /// Power the peripheral up again after `disable`; the reception has to be started again.
+    pub fn enable(&mut self) {
+        self.uarte.enable.write(|w| w.enable().enabled());
+    }
+}
+
+impl<'a> core::fmt::Write for Uarte<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes());
+
+        Ok(())
+    }
+}
+
+/// Handle the events of the UARTE; call from the handler of `UARTE0_UART0`.
+pub fn on_interrupt() {
+    let device = unsafe { pac::Peripherals::steal() };
+    let uarte = &device.UARTE0;
+
+    interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+
+        if uarte.events_endtx.read().events_endtx().is_generated() {
+            uarte
+                .events_endtx
+                .write(|w| w.events_endtx().not_generated());
+
+            let sent = state.sending;
+
+            state.tx.consume(sent);
+            state.sending = 0;
+            start_tx(uarte, &mut state);
+        }
+
+        if uarte.events_endrx.read().events_endrx().is_generated() {
+            end_rx(uarte, &mut state);
+        }
+
+        if uarte
+            .events_rxstarted
+            .read()
+            .events_rxstarted()
+            .is_generated()
+        {
+            uarte
+                .events_rxstarted
+                .write(|w| w.events_rxstarted().not_generated());
+
+            // the buffer to continue with once the current one is full
+            let next = 1 - state.rx_current;
+
+            set_rx_buffer(uarte, &mut state.rx_buffers[next]);
+        }
+    });
+}
+
+/// Send the next chunk of the ring buffer unless a transmission is in progress.
+fn start_tx(uarte: &pac::UARTE0, state: &mut State) {
+    if state.sending > 0 || state.tx.len == 0 {
+        return;
+    }
+
+    let chunk = state.tx.contiguous();
+
+    uarte
+        .txd
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(chunk.as_ptr() as u32) });
+    uarte
+        .txd
+        .maxcnt
+        .write(|w| unsafe { w.maxcnt().bits(chunk.len() as _) });
+    state.sending = chunk.len();
+    uarte
+        .events_endtx
+        .write(|w| w.events_endtx().not_generated());
+    uarte.intenset.write(|w| w.endtx().set());
+    compiler_fence(Ordering::SeqCst);
+    uarte.tasks_starttx.write(|w| w.tasks_starttx().trigger());
+}
+
+/// Move the bytes of the full (or stopped) DMA buffer into the ring buffer.
+fn end_rx(uarte: &pac::UARTE0, state: &mut State) {
+    uarte
+        .events_endrx
+        .write(|w| w.events_endrx().not_generated());
+    compiler_fence(Ordering::SeqCst);
+
+    let amount = (uarte.rxd.amount.read().amount().bits() as usize).min(RX_CHUNK_LEN);
+    let current = state.rx_current;
+    let received = state.rx_buffers[current];
+
+    // bytes that do not fit are dropped
+    state.rx.push(&received[..amount]);
+    state.rx_current = 1 - current;
+}
+
+fn set_rx_buffer(uarte: &pac::UARTE0, buffer: &mut [u8; RX_CHUNK_LEN]) {
+    uarte
+        .rxd
+        .ptr
+        .write(|w| unsafe { w.ptr().bits(buffer.as_mut_ptr() as u32) });
+    uarte
+        .rxd
+        .maxcnt
+        .write(|w| unsafe { w.maxcnt().bits(RX_CHUNK_LEN as _) });
+}
+
+/// Poll for an event for at most `STOP_TIMEOUT_MUS`; returns `false` if it did not occur.
+fn poll<F: Fn() -> bool>(event: F) -> bool {
+    for _ in 0..STOP_TIMEOUT_MUS {
+        if event() {
+            return true;
+        }
+
+        cortex_m::asm::delay(CYCLES_PER_MUS);
+    }
+
+    false
+}
@@ -1,10 +1,31 @@
 pub fn get_key() -> [u8; 16] {
-    u128::from_str_radix(
-        option_env!("KEY").unwrap_or("A0B1C2D3E4F5061728394A5B6C7D8E9F"),
-        16,
-    )
-    .unwrap()
-    .to_le_bytes()
+    parse_key(option_env!("KEY").unwrap_or("A0B1C2D3E4F5061728394A5B6C7D8E9F"))
+}
+
+/// Get the key for a logical address.
+///
+/// The key is taken from `KEY_PIPE<n>` at build time; if it is not set, the key from `get_key` is used.
+pub fn get_pipe_key(pipe: u8) -> [u8; 16] {
+    let key = match pipe {
+        0 => option_env!("KEY_PIPE0"),
+        1 => option_env!("KEY_PIPE1"),
+        2 => option_env!("KEY_PIPE2"),
+        3 => option_env!("KEY_PIPE3"),
+        4 => option_env!("KEY_PIPE4"),
+        5 => option_env!("KEY_PIPE5"),
+        6 => option_env!("KEY_PIPE6"),
+        7 => option_env!("KEY_PIPE7"),
+        _ => None,
+    };
+
+    match key {
+        Some(key) => parse_key(key),
+        None => get_key(),
+    }
+}
+
+fn parse_key(key: &str) -> [u8; 16] {
+    u128::from_str_radix(key, 16).unwrap().to_le_bytes()
 }
 
 pub fn copy_into_array(source: &[u8], target: &mut [u8]) {